@@ -1,4 +1,7 @@
-use camloc_common::cv::{calibrate, draw_charuco_board, find_board, generate_board, CameraParams};
+use camloc_common::cv::{
+    calibrate, draw_charuco_board, find_board, generate_board, BoardConfig, CameraParams,
+    MarkerDictionary,
+};
 use clap::{Parser, Subcommand};
 use opencv::{
     self, core, highgui, imgcodecs, objdetect::CharucoBoard, prelude::*, videoio::VideoCapture,
@@ -14,6 +17,18 @@ struct Args {
     #[arg(short, long, default_value_t = 7)]
     cols: u8,
 
+    /// The marker dictionary of the board
+    #[arg(short, long, default_value_t = MarkerDictionary::default())]
+    dictionary: MarkerDictionary,
+
+    /// Side length of a printed square (m)
+    #[arg(long, default_value_t = 0.04)]
+    square_length: f32,
+
+    /// Side length of a printed marker (m)
+    #[arg(long, default_value_t = 0.02)]
+    marker_length: f32,
+
     /// The action to take
     #[command(subcommand)]
     command: CLICommand,
@@ -85,7 +100,15 @@ enum CalibrateCommand {
 
 fn main() -> opencv::Result<()> {
     let args = Args::parse();
-    let board = generate_board(args.cols, args.rows)?;
+    let board = generate_board(
+        args.cols,
+        args.rows,
+        &BoardConfig {
+            dictionary: args.dictionary,
+            square_length: args.square_length,
+            marker_length: args.marker_length,
+        },
+    )?;
 
     match args.command {
         CLICommand::Generate {
@@ -0,0 +1,36 @@
+use anyhow::{anyhow, Result};
+use camloc_common::cv::{get_apriltag_parameters, MarkerDictionary};
+use opencv::{core::Rect, prelude::*, types};
+
+use crate::aruco::{ArucoFinder, MarkerFinder};
+
+/// Finds AprilTags with opencv's aruco module
+///
+/// The tag ids are used as the cube ids, the same way as aruco markers
+pub struct AprilTagFinder {
+    inner: ArucoFinder,
+}
+
+impl AprilTagFinder {
+    pub fn new(cube: [u16; 4], family: MarkerDictionary) -> Result<Self> {
+        if !family.is_apriltag() {
+            return Err(anyhow!(
+                "The apriltag detector needs an apriltag dictionary, got `{family}`"
+            ));
+        }
+
+        Ok(Self {
+            inner: ArucoFinder::new(cube, family, &get_apriltag_parameters()?)?,
+        })
+    }
+}
+
+impl MarkerFinder for AprilTagFinder {
+    fn find(
+        &mut self,
+        frame: &Mat,
+        roi: Option<Rect>,
+    ) -> opencv::Result<Option<(u16, types::VectorOfPoint2f)>> {
+        self.inner.find(frame, roi)
+    }
+}
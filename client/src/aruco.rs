@@ -1,31 +1,58 @@
 use opencv::{
-    self,
+    self, aruco, calib3d,
     core::{self, Ptr, Rect},
     objdetect,
     prelude::*,
-    tracking::{self, TrackerKCF},
+    tracking::{self, TrackerCSRT, TrackerKCF},
     types,
-    video::TrackerTrait,
+    video::{self, TrackerMIL, TrackerTrait},
 };
 
-use crate::util::{self, Center, Color};
-use camloc_common::{cv::get_aruco_dictionary, hosts::ClientData};
+use std::time::{Duration, Instant};
 
-pub struct Detector {
+use crate::{
+    apriltag::AprilTagFinder,
+    util::{self, Center, Color},
+};
+use camloc_common::{
+    cv::{
+        get_aruco_dictionary, get_detector_parameters, marker_center, CameraParams, FullCameraInfo,
+        MarkerDictionary,
+    },
+    hosts::{ClientData, MarkerPose},
+};
+
+/// Finds the markers of the cube in a frame
+pub trait MarkerFinder {
+    /// finds the first marker of the cube (only inside `roi` if given),
+    /// returns its id and corners
+    fn find(
+        &mut self,
+        frame: &Mat,
+        roi: Option<Rect>,
+    ) -> opencv::Result<Option<(u16, types::VectorOfPoint2f)>>;
+}
+
+/// Finds markers with opencv's aruco detector
+pub struct ArucoFinder {
     detector: objdetect::ArucoDetector,
     corners: types::VectorOfVectorOfPoint2f,
     marker_ids: core::Vector<i32>,
-    cube: [u8; 4],
+    cube: [u16; 4],
 }
 
-impl Detector {
+impl ArucoFinder {
     /// setup new aruco detector
     /// generate targets with: https://chev.me/arucogen/
-    pub fn new(cube: [u8; 4]) -> opencv::Result<Self> {
+    pub fn new(
+        cube: [u16; 4],
+        dictionary: MarkerDictionary,
+        parameters: &objdetect::DetectorParameters,
+    ) -> opencv::Result<Self> {
         Ok(Self {
             detector: objdetect::ArucoDetector::new(
-                &get_aruco_dictionary()?,
-                &objdetect::DetectorParameters::default()?,
+                &get_aruco_dictionary(dictionary)?,
+                parameters,
                 objdetect::RefineParameters {
                     min_rep_distance: 0.5,
                     error_correction_rate: 1.0,
@@ -37,32 +64,114 @@ impl Detector {
             cube,
         })
     }
+}
 
-    pub fn detect(
+impl MarkerFinder for ArucoFinder {
+    fn find(
         &mut self,
         frame: &Mat,
-        rect: Option<&mut core::Rect>,
-        draw: Option<&mut Mat>,
-    ) -> opencv::Result<Option<ClientData>> {
-        self.detector.detect_markers(
-            frame,
-            &mut self.corners,
-            &mut self.marker_ids,
-            &mut core::no_array(),
-        )?;
+        roi: Option<Rect>,
+    ) -> opencv::Result<Option<(u16, types::VectorOfPoint2f)>> {
+        let offset = if let Some(roi) = roi {
+            self.detector.detect_markers(
+                &Mat::roi(frame, roi)?,
+                &mut self.corners,
+                &mut self.marker_ids,
+                &mut core::no_array(),
+            )?;
+            core::Point2f::new(roi.x as f32, roi.y as f32)
+        } else {
+            self.detector.detect_markers(
+                frame,
+                &mut self.corners,
+                &mut self.marker_ids,
+                &mut core::no_array(),
+            )?;
+            core::Point2f::default()
+        };
 
+        // opencv's ids are i32, they're compared as such so none of them is truncated
         let Some((index, marker_id)) = self
             .marker_ids
             .iter()
             .enumerate()
-            .find(|(_, s)| self.cube.contains(&(*s as u8)))
+            .find_map(|(i, id)| Some((i, self.cube.iter().find(|c| **c as i32 == id)?)))
         else {
             return Ok(None);
         };
-        let marker_id = marker_id as u8;
 
-        let bounding = self.corners.get(index)?;
-        let center = util::avg_corners(&bounding);
+        let bounding = self
+            .corners
+            .get(index)?
+            .iter()
+            .map(|p| p + offset)
+            .collect();
+
+        Ok(Some((*marker_id, bounding)))
+    }
+}
+
+/// Which marker finder to use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DetectorKind {
+    /// apriltag for apriltag dictionaries, aruco otherwise
+    Auto,
+    Aruco,
+    #[value(name = "apriltag")]
+    AprilTag,
+}
+
+impl DetectorKind {
+    pub fn finder(
+        self,
+        cube: [u16; 4],
+        dictionary: MarkerDictionary,
+    ) -> anyhow::Result<Box<dyn MarkerFinder>> {
+        Ok(match self {
+            DetectorKind::Auto if dictionary.is_apriltag() => {
+                Box::new(AprilTagFinder::new(cube, dictionary)?)
+            }
+            DetectorKind::Auto | DetectorKind::Aruco => Box::new(ArucoFinder::new(
+                cube,
+                dictionary,
+                &get_detector_parameters()?,
+            )?),
+            DetectorKind::AprilTag => Box::new(AprilTagFinder::new(cube, dictionary)?),
+        })
+    }
+}
+
+pub struct Detector {
+    finder: Box<dyn MarkerFinder>,
+    params: CameraParams,
+}
+
+impl Detector {
+    pub fn new(finder: Box<dyn MarkerFinder>, params: CameraParams) -> Self {
+        Self { finder, params }
+    }
+
+    /// finds a marker of the cube without measuring it
+    pub fn find(
+        &mut self,
+        frame: &Mat,
+        roi: Option<Rect>,
+    ) -> opencv::Result<Option<(u16, types::VectorOfPoint2f)>> {
+        self.finder.find(frame, roi)
+    }
+
+    pub fn detect(
+        &mut self,
+        frame: &Mat,
+        roi: Option<Rect>,
+        rect: Option<&mut core::Rect>,
+        draw: Option<&mut Mat>,
+    ) -> opencv::Result<Option<ClientData>> {
+        let Some((marker_id, bounding)) = self.finder.find(frame, roi)? else {
+            return Ok(None);
+        };
+
+        let center = util::round_point(marker_center(&bounding)?);
         let brect = util::bounding_to_rect(&bounding, 0);
 
         if let Some(rect) = rect {
@@ -75,52 +184,263 @@ impl Detector {
             util::rect(draw, brect, Color::Yellow)?;
         }
 
-        Ok(Some(ClientData {
-            marker_id,
-            x_position: util::relative_x(frame, center)?,
+        let undistorted = util::undistort_points(&bounding, &self.params)?;
+        let bearing = util::bearing(marker_center(&undistorted)?);
+        let height = util::angular_size(util::marker_height(&undistorted)?);
+
+        Ok(Some(
+            ClientData::new(marker_id, bearing).with_marker_height(height),
+        ))
+    }
+
+    /// estimates the full pose of the marker relative to the camera
+    pub fn detect_pose(
+        &mut self,
+        frame: &Mat,
+        roi: Option<Rect>,
+        rect: Option<&mut core::Rect>,
+        draw: Option<&mut Mat>,
+    ) -> opencv::Result<Option<MarkerPose>> {
+        let Some((marker_id, bounding)) = self.finder.find(frame, roi)? else {
+            return Ok(None);
+        };
+
+        if let Some(rect) = rect {
+            *rect = util::bounding_to_rect(&bounding, 0);
+        }
+
+        let mut rvecs = types::VectorOfVec3d::new();
+        let mut tvecs = types::VectorOfVec3d::new();
+
+        // unit marker size, the server scales the translation
+        aruco::estimate_pose_single_markers(
+            &types::VectorOfVectorOfPoint2f::from_iter([bounding.clone()]),
+            1.,
+            &self.params.camera_matrix,
+            &self.params.dist_coeffs,
+            &mut rvecs,
+            &mut tvecs,
+            &mut core::no_array(),
+            &aruco::EstimateParameters::create()?,
+        )?;
+        let (rvec, tvec) = (rvecs.get(0)?, tvecs.get(0)?);
+
+        if let Some(draw) = draw {
+            util::draw_bounds(draw, &bounding, Color::Green)?;
+            calib3d::draw_frame_axes(
+                draw,
+                &self.params.camera_matrix,
+                &self.params.dist_coeffs,
+                &rvec,
+                &tvec,
+                0.5,
+                2,
+            )?;
+        }
+
+        Ok(Some(MarkerPose::new(marker_id, rvec.0, tvec.0)))
+    }
+}
+
+/// The tracking algorithm used between detections
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TrackerKind {
+    Kcf,
+    Csrt,
+    Mil,
+    /// run the detector on every frame
+    None,
+}
+
+enum TrackerBackend {
+    Kcf(Ptr<TrackerKCF>),
+    Csrt(Ptr<TrackerCSRT>),
+    Mil(Ptr<TrackerMIL>),
+}
+
+impl TrackerBackend {
+    fn create(kind: TrackerKind) -> opencv::Result<Option<Self>> {
+        Ok(Some(match kind {
+            TrackerKind::Kcf => {
+                Self::Kcf(TrackerKCF::create(tracking::TrackerKCF_Params::default()?)?)
+            }
+            TrackerKind::Csrt => Self::Csrt(TrackerCSRT::create(
+                &tracking::TrackerCSRT_Params::default()?,
+            )?),
+            TrackerKind::Mil => {
+                Self::Mil(TrackerMIL::create(video::TrackerMIL_Params::default()?)?)
+            }
+            TrackerKind::None => return Ok(None),
         }))
     }
+
+    fn init(&mut self, frame: &Mat, rect: Rect) -> opencv::Result<()> {
+        match self {
+            Self::Kcf(t) => t.init(frame, rect),
+            Self::Csrt(t) => t.init(frame, rect),
+            Self::Mil(t) => t.init(frame, rect),
+        }
+    }
+
+    fn update(&mut self, frame: &Mat, rect: &mut Rect) -> opencv::Result<bool> {
+        match self {
+            Self::Kcf(t) => t.update(frame, rect),
+            Self::Csrt(t) => t.update(frame, rect),
+            Self::Mil(t) => t.update(frame, rect),
+        }
+    }
 }
 
 pub struct Tracker {
-    kcf: Ptr<TrackerKCF>,
+    kind: TrackerKind,
+    backend: Option<TrackerBackend>,
     /// bounding box of the tracked area
     pub rect: Rect,
+    /// the tracked area when the tracker was initialized
+    initial_rect: Rect,
+    /// apparent height of the marker when the tracker was initialized
+    marker_height: Option<f64>,
+    params: CameraParams,
 }
 
 impl Tracker {
-    pub fn new() -> opencv::Result<Self> {
+    pub fn new(kind: TrackerKind, params: CameraParams) -> opencv::Result<Self> {
         Ok(Self {
-            kcf: Self::reinit(&Mat::default(), Rect::default())?,
+            backend: Self::reinit(kind, &Mat::default(), Rect::default())?,
             rect: Rect::default(),
+            initial_rect: Rect::default(),
+            marker_height: None,
+            params,
+            kind,
         })
     }
 
-    /// creates a new `TrackerKCF` struct (because calling `init` on the same instance causes segfaults for whatever reason)
-    fn reinit(frame: &Mat, rect: Rect) -> opencv::Result<Ptr<TrackerKCF>> {
-        let mut tracker = TrackerKCF::create(tracking::TrackerKCF_Params::default()?)?;
-        if !rect.empty() {
-            tracker.init(frame, rect)?
+    /// creates a new tracker (because calling `init` on the same instance causes segfaults for whatever reason)
+    fn reinit(
+        kind: TrackerKind,
+        frame: &Mat,
+        rect: Rect,
+    ) -> opencv::Result<Option<TrackerBackend>> {
+        let mut tracker = TrackerBackend::create(kind)?;
+        if let Some(tracker) = &mut tracker {
+            if !rect.empty() {
+                tracker.init(frame, rect)?
+            }
         }
         Ok(tracker)
     }
 
-    pub fn init(&mut self, frame: &Mat) -> opencv::Result<()> {
-        self.kcf = Self::reinit(frame, self.rect)?;
+    /// `marker_height` is the apparent height of the marker in `rect`
+    pub fn init(&mut self, frame: &Mat, marker_height: Option<f64>) -> opencv::Result<()> {
+        self.backend = Self::reinit(self.kind, frame, self.rect)?;
+        self.initial_rect = self.rect;
+        self.marker_height = marker_height;
         Ok(())
     }
 
+    pub fn is_enabled(&self) -> bool {
+        self.kind != TrackerKind::None
+    }
+
     /// returns None if lost object
-    pub fn track(&mut self, frame: &Mat, draw: Option<&mut Mat>) -> opencv::Result<Option<f64>> {
-        if self.kcf.update(&frame, &mut self.rect)? {
-            if let Some(draw) = draw {
-                util::rect(draw, self.rect, Color::Cyan)?;
-                util::draw_x(draw, self.rect.center(), Color::Red)?;
+    pub fn track(
+        &mut self,
+        frame: &Mat,
+        marker_id: u16,
+        draw: Option<&mut Mat>,
+    ) -> opencv::Result<Option<ClientData>> {
+        let Some(backend) = &mut self.backend else {
+            return Ok(None);
+        };
+
+        if !backend.update(frame, &mut self.rect)? {
+            return Ok(None);
+        }
+
+        if let Some(draw) = draw {
+            util::rect(draw, self.rect, Color::Cyan)?;
+            util::draw_x(draw, self.rect.center(), Color::Red)?;
+        }
+
+        let (x, y) = (self.rect.x as f32, self.rect.y as f32);
+        let (w, h) = (self.rect.width as f32, self.rect.height as f32);
+        let center = util::undistort_points(
+            &types::VectorOfPoint2f::from_iter([core::Point2f::new(x + 0.5 * w, y + 0.5 * h)]),
+            &self.params,
+        )?
+        .get(0)?;
+        let data = ClientData::new(marker_id, util::bearing(center));
+
+        // the tracked area is axis aligned, it's larger than the marker if the marker is rotated,
+        // so only its change in size is used to scale the marker's height at the last detection
+        let initial = self.initial_rect;
+        Ok(Some(match self.marker_height {
+            Some(height) if !initial.empty() => {
+                let scale = (self.rect.area() as f64 / initial.area() as f64).sqrt();
+                data.with_marker_height(util::angular_size(2. * (0.5 * height).tan() * scale))
             }
+            _ => data,
+        }))
+    }
+}
 
-            Ok(Some(util::relative_x(frame, self.rect.center())?))
-        } else {
-            Ok(None)
+/// How long the steps of the last detection took,
+/// `None` for the ones that didn't run
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StepTimes {
+    pub detection: Option<Duration>,
+    pub tracking: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TrackingOptions {
+    pub tracker: TrackerKind,
+    /// run the detector after this many tracked frames (0 means never)
+    pub redetect_interval: u32,
+    /// check that the tracked area still has a marker of the cube
+    /// every this many tracked frames (0 means never)
+    pub verify_interval: u32,
+    /// the most the tracked area and aspect ratio may change
+    /// relative to the last detection before re-detecting
+    pub max_change: f64,
+    /// how much the search region extends past the last known marker
+    /// relative to its size (0 always searches the whole frame)
+    pub search_margin: f64,
+    /// how many times the search region is doubled on misses
+    /// before searching the whole frame
+    pub search_widenings: u32,
+}
+
+/// Where to look for the marker, around where it was last seen,
+/// widening on every miss until the whole frame is searched
+#[derive(Debug, Clone, Copy, Default)]
+struct SearchRegion {
+    last_seen: Option<Rect>,
+    misses: u32,
+}
+
+impl SearchRegion {
+    /// `None` means the whole frame
+    fn roi(&self, size: core::Size, options: &TrackingOptions) -> Option<Rect> {
+        let last_seen = self.last_seen?;
+        if options.search_margin <= 0. || self.misses > options.search_widenings {
+            return None;
+        }
+
+        let margin = options.search_margin * 2f64.powi(self.misses as i32);
+        let roi = util::clamp_rect(util::grow_rect(last_seen, margin), size);
+
+        Some(roi).filter(|r| !r.empty() && r.size() != size)
+    }
+
+    fn update(&mut self, found: Option<Rect>) {
+        match found {
+            Some(rect) => {
+                self.last_seen = Some(rect);
+                self.misses = 0;
+            }
+            None if self.last_seen.is_some() => self.misses += 1,
+            None => (),
         }
     }
 }
@@ -129,42 +449,159 @@ pub struct Aruco {
     tracked_object: Option<ClientData>,
     detector: Detector,
     tracker: Tracker,
-    // TODO: sanity checks
+    options: TrackingOptions,
+    /// bounding box of the last detection
+    detected_rect: Rect,
+    frames_since_detection: u32,
+    search: SearchRegion,
+    last_times: StepTimes,
 }
 
 impl Aruco {
-    pub fn new(cube: [u8; 4]) -> opencv::Result<Aruco> {
+    pub fn new(
+        finder: Box<dyn MarkerFinder>,
+        calibration: &FullCameraInfo,
+        options: TrackingOptions,
+    ) -> opencv::Result<Aruco> {
         Ok(Self {
-            detector: Detector::new(cube)?,
-            tracker: Tracker::new()?,
+            detector: Detector::new(finder, calibration.params.clone()),
+            tracker: Tracker::new(options.tracker, calibration.params.clone())?,
+            detected_rect: Rect::default(),
+            frames_since_detection: 0,
+            search: SearchRegion::default(),
+            last_times: StepTimes::default(),
             tracked_object: None,
+            options,
         })
     }
 
     pub fn detect(
         &mut self,
         frame: &Mat,
-        draw: Option<&mut Mat>,
+        mut draw: Option<&mut Mat>,
     ) -> opencv::Result<Option<ClientData>> {
-        self.tracked_object = if let Some(ClientData { marker_id, .. }) = self.tracked_object {
-            let track = self.tracker.track(frame, draw)?;
+        self.last_times = StepTimes::default();
 
-            track.map(|x| ClientData {
-                marker_id,
-                x_position: x,
-            })
-        } else {
-            let res = self
-                .detector
-                .detect(frame, Some(&mut self.tracker.rect), draw)?;
+        if let Some(ClientData { marker_id, .. }) = self.tracked_object {
+            let redetect = self.options.redetect_interval != 0
+                && self.frames_since_detection >= self.options.redetect_interval;
+
+            if !redetect {
+                self.frames_since_detection += 1;
+
+                let start = Instant::now();
+                let tracked = self.tracker.track(frame, marker_id, draw.as_deref_mut())?;
+                self.last_times.tracking = Some(start.elapsed());
 
-            if res.is_some() {
-                self.tracker.init(frame)?;
+                let verify = self.options.verify_interval != 0
+                    && self.frames_since_detection % self.options.verify_interval == 0;
+
+                if tracked.is_some()
+                    && self.is_plausible(frame, self.tracker.rect)?
+                    && (!verify || self.still_tracking(frame, marker_id)?)
+                {
+                    self.tracked_object = tracked;
+                    self.search.update(Some(self.tracker.rect));
+                    return Ok(tracked);
+                }
             }
+        }
+
+        let start = Instant::now();
+
+        let roi = self.search_roi(frame, draw.as_deref_mut())?;
+        let res = self.detector.detect(
+            frame,
+            roi,
+            Some(&mut self.tracker.rect),
+            draw.as_deref_mut(),
+        )?;
+        self.search.update(res.as_ref().map(|_| self.tracker.rect));
+
+        self.frames_since_detection = 0;
+        if res.is_some() {
+            self.detected_rect = self.tracker.rect;
+            if self.tracker.is_enabled() {
+                let height = res.and_then(|r| r.marker_height);
+                self.tracker.init(frame, height)?;
+            }
+        }
+
+        // without a tracker every frame is detected anyway
+        self.tracked_object = res.filter(|_| self.tracker.is_enabled());
+        self.last_times.detection = Some(start.elapsed());
+
+        Ok(res)
+    }
+
+    /// the region to run the detector on, shown on the drawing
+    fn search_roi(&self, frame: &Mat, draw: Option<&mut Mat>) -> opencv::Result<Option<Rect>> {
+        let roi = self.search.roi(frame.size()?, &self.options);
+        if let (Some(roi), Some(draw)) = (roi, draw) {
+            util::rect(draw, roi, Color::Magenta)?;
+        }
+        Ok(roi)
+    }
+
+    /// checks whether the tracked area could still be the detected marker
+    fn is_plausible(&self, frame: &Mat, rect: Rect) -> opencv::Result<bool> {
+        let size = frame.size()?;
+        if rect.empty()
+            || rect.x < 0
+            || rect.y < 0
+            || rect.x + rect.width > size.width
+            || rect.y + rect.height > size.height
+        {
+            return Ok(false);
+        }
+
+        let d = self.detected_rect;
+        if d.empty() {
+            return Ok(true);
+        }
+
+        let within =
+            |ratio: f64| (1. / self.options.max_change..=self.options.max_change).contains(&ratio);
+
+        let area = rect.area() as f64 / d.area() as f64;
+        let aspect = (rect.width as f64 / rect.height as f64) / (d.width as f64 / d.height as f64);
 
-            res
+        Ok(within(area) && within(aspect))
+    }
+
+    /// checks whether the tracked marker can still be found around the tracked area,
+    /// so that a tracker that drifted onto the background is dropped
+    fn still_tracking(&mut self, frame: &Mat, marker_id: u16) -> opencv::Result<bool> {
+        let roi = util::clamp_rect(
+            util::grow_rect(self.tracker.rect, self.options.search_margin.max(0.25)),
+            frame.size()?,
+        );
+        Ok(self.detector.find(frame, Some(roi))?.map(|(id, _)| id) == Some(marker_id))
+    }
+
+    /// the tracker can't follow rotations, so poses are always detected
+    pub fn detect_pose(
+        &mut self,
+        frame: &Mat,
+        mut draw: Option<&mut Mat>,
+    ) -> opencv::Result<Option<MarkerPose>> {
+        let start = Instant::now();
+
+        let roi = self.search_roi(frame, draw.as_deref_mut())?;
+        let mut rect = Rect::default();
+        let res = self
+            .detector
+            .detect_pose(frame, roi, Some(&mut rect), draw)?;
+        self.search.update(res.as_ref().map(|_| rect));
+
+        self.last_times = StepTimes {
+            detection: Some(start.elapsed()),
+            tracking: None,
         };
+        Ok(res)
+    }
 
-        Ok(self.tracked_object)
+    pub fn last_times(&self) -> StepTimes {
+        self.last_times
     }
 }
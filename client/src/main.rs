@@ -1,40 +1,75 @@
+mod apriltag;
 mod aruco;
+mod preview;
+mod source;
+mod telemetry;
 mod util;
 
-use crate::aruco::Aruco;
-use anyhow::Result;
+use crate::{
+    aruco::{Aruco, DetectorKind, TrackerKind, TrackingOptions},
+    preview::Preview,
+    source::{CaptureSettings, FrameSource, SourceSpec},
+    telemetry::Telemetry,
+};
+use anyhow::{anyhow, Result};
 use camloc_common::{
-    cv::FullCameraInfo,
+    cv::{BoardConfig, CachedCalibration, CameraIdentity, FullCameraInfo, MarkerDictionary},
     hosts::{
         constants::{MAIN_PORT, ORGANIZER_STARTER_PORT},
-        Command, HostInfo, HostState, HostType,
+        ClientData, Command, HostInfo, HostState, HostType,
     },
-    Position,
-};
-use opencv::{
-    self, core, highgui,
-    prelude::*,
-    videoio::{self, VideoCapture},
+    ClientIdentity, Position,
 };
+use opencv::{self, core, highgui, prelude::*};
 use std::{
-    fs::File,
-    io::{Read, Write},
+    io::{ErrorKind, Read, Write},
     net::{IpAddr, SocketAddr, TcpStream, UdpSocket},
-    time::Duration,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 const BUF_SIZE: usize = 2048;
 
+/// Settings of the detection loop
+struct Options {
+    gui: bool,
+    detector: DetectorKind,
+    tracking: TrackingOptions,
+    pose: bool,
+    /// how often to send telemetry to the server
+    telemetry_interval: Option<Duration>,
+    capture: CaptureSettings,
+    on_resolution_mismatch: ResolutionMismatch,
+}
+
+/// What to do when the frames don't have the resolution the camera was calibrated at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum ResolutionMismatch {
+    /// Ignore the cached calibration and calibrate again
+    Recalibrate,
+    /// Don't start
+    Refuse,
+    /// Scale the intrinsics to the new resolution
+    Rescale,
+}
+
 struct Config {
     calibration: FullCameraInfo,
     server: SocketAddr,
-    cube: [u8; 4],
+    cube: [u16; 4],
+    dictionary: MarkerDictionary,
+    /// whether the organizer calibrated the camera just now
+    calibrated: bool,
 }
 
 impl Config {
     fn from_organizer(
         r: &mut impl Read,
-        cached_calibration: &Option<FullCameraInfo>,
+        calibration: Option<&FullCameraInfo>,
     ) -> Result<(Self, Position)> {
         let mut buf = vec![0; 26];
         r.read_exact(&mut buf)?;
@@ -52,26 +87,81 @@ impl Config {
 
         let server = SocketAddr::new(ip.parse()?, MAIN_PORT);
 
-        let calibration = if let Some(c) = cached_calibration {
-            c.clone()
-        } else {
-            FullCameraInfo::from_be_bytes(r)?
+        let (calibration, calibrated) = match calibration {
+            Some(c) => (c.clone(), false),
+            None => (FullCameraInfo::from_be_bytes(r)?, true),
         };
 
-        let mut cube = [0; 4];
+        let mut cube = [0; 8];
         r.read_exact(&mut cube)?;
 
+        let board = BoardConfig::from_be_bytes(r)?;
+
         Ok((
             Self {
                 calibration,
                 server,
-                cube: cube.map(u8::from_be),
+                cube: [0, 1, 2, 3].map(|i| u16::from_be_bytes([cube[2 * i], cube[2 * i + 1]])),
+                dictionary: board.dictionary,
+                calibrated,
             },
             Position::new(x, y, rotation),
         ))
     }
 }
 
+/// A camera of the client, registered with the server as a separate client
+struct Camera {
+    /// shown in the logs and the window title
+    name: String,
+    /// the camera's own socket, the server tells cameras apart by its port
+    socket: UdpSocket,
+    info: Mutex<HostInfo>,
+    /// what the organizer is told when it asks which camera this is
+    identity: ClientIdentity,
+}
+
+impl Camera {
+    fn set_state(&self, host_state: HostState, calibrated: bool) {
+        *self.info.lock().unwrap() = HostInfo {
+            host_type: HostType::Client { calibrated },
+            host_state,
+        };
+    }
+
+    fn reply_to_ping(&self, addr: SocketAddr) -> Result<()> {
+        let info = *self.info.lock().unwrap();
+        self.socket.send_to(&[info.try_into()?], addr)?;
+        Ok(())
+    }
+
+    fn reply_to_identify(&self, addr: SocketAddr) -> Result<()> {
+        let identity = Command::Identity {
+            device: &self.identity.device,
+            calibration: &self.identity.calibration,
+        };
+        self.socket
+            .send_to(&Into::<Vec<u8>>::into(identity), addr)?;
+        Ok(())
+    }
+}
+
+/// What a camera thread needs besides the shared `Camera`
+struct CameraSetup {
+    source: SourceSpec,
+    calibration_cache: String,
+    cached_calibration: Option<CachedCalibration>,
+    preview_port: Option<u16>,
+}
+
+/// The buffers of a camera's detection loop
+struct Buffers {
+    buf: [u8; BUF_SIZE],
+    frame: Mat,
+    draw: Option<Mat>,
+    preview: Option<Preview>,
+}
+
 fn main() -> Result<()> {
     let args = {
         use clap::Parser;
@@ -83,74 +173,367 @@ fn main() -> Result<()> {
             #[arg(long, default_value_t = 0u16)]
             camera_index: u16,
 
-            /// Calibration cache file
-            #[arg(long, default_value = ".calib")]
-            calibration_cache: String,
+            /// Where to get frames from, once for every camera, overrides the camera index
+            /// (camera:<index>, video:<file>, images:<directory> or synthetic:<marker id>[:<dictionary>])
+            #[arg(long)]
+            source: Vec<SourceSpec>,
+
+            /// Calibration cache file, once for every camera
+            /// (defaults to .calib for the first camera, .calib-<n> for the rest)
+            #[arg(long)]
+            calibration_cache: Vec<String>,
 
             /// Show what's happening
             #[arg(short, long, default_value_t = false)]
             gui: bool,
+
+            /// Serve the annotated frames and the detection state over http on this port
+            /// (the next ports are used for the rest of the cameras)
+            #[arg(long)]
+            preview_port: Option<u16>,
+
+            /// Send the full marker pose instead of just the bearing
+            #[arg(long, default_value_t = false)]
+            pose: bool,
+
+            /// The marker detector to use
+            #[arg(long, value_enum, default_value_t = DetectorKind::Auto)]
+            detector: DetectorKind,
+
+            /// The tracker to use between detections
+            #[arg(long, value_enum, default_value_t = TrackerKind::Kcf)]
+            tracker: TrackerKind,
+
+            /// Re-run the detector after this many tracked frames (0 to disable)
+            #[arg(long, default_value_t = 30)]
+            redetect_interval: u32,
+
+            /// Check that the tracked area still has a cube marker every this many tracked frames
+            /// (0 to disable)
+            #[arg(long, default_value_t = 5)]
+            verify_interval: u32,
+
+            /// Re-run the detector if the tracked area or aspect ratio changes more than this factor
+            #[arg(long, default_value_t = 1.5)]
+            max_tracking_change: f64,
+
+            /// Search this far around the last known marker position, relative to its size
+            /// (0 to always search the whole frame)
+            #[arg(long, default_value_t = 0.5)]
+            search_margin: f64,
+
+            /// Double the search region this many times on misses before searching the whole frame
+            #[arg(long, default_value_t = 3)]
+            search_widenings: u32,
+
+            /// Send performance telemetry to the server this often (seconds, 0 to disable)
+            #[arg(long, default_value_t = 5.)]
+            telemetry_interval: f64,
+
+            /// Capture width to request from the camera
+            #[arg(long)]
+            width: Option<u32>,
+
+            /// Capture height to request from the camera
+            #[arg(long)]
+            height: Option<u32>,
+
+            /// Frame rate to request from the camera
+            #[arg(long)]
+            fps: Option<f64>,
+
+            /// Manual exposure, turns auto exposure off (in the driver's units)
+            #[arg(long)]
+            exposure: Option<f64>,
+
+            /// Manual gain (in the driver's units)
+            #[arg(long)]
+            gain: Option<f64>,
+
+            /// Manual focus, turns autofocus off (in the driver's units)
+            #[arg(long)]
+            focus: Option<f64>,
+
+            /// What to do if the camera's resolution differs from the one it was calibrated at
+            #[arg(long, value_enum, default_value_t = ResolutionMismatch::Recalibrate)]
+            on_resolution_mismatch: ResolutionMismatch,
         }
 
         Args::parse()
     };
+    let options = Options {
+        gui: args.gui,
+        detector: args.detector,
+        tracking: TrackingOptions {
+            tracker: args.tracker,
+            redetect_interval: args.redetect_interval,
+            verify_interval: args.verify_interval,
+            max_change: args.max_tracking_change,
+            search_margin: args.search_margin,
+            search_widenings: args.search_widenings,
+        },
+        pose: args.pose,
+        telemetry_interval: Some(args.telemetry_interval)
+            .filter(|i| *i > 0.)
+            .map(Duration::from_secs_f64),
+        capture: CaptureSettings {
+            width: args.width,
+            height: args.height,
+            fps: args.fps,
+            exposure: args.exposure,
+            gain: args.gain,
+            focus: args.focus,
+        },
+        on_resolution_mismatch: args.on_resolution_mismatch,
+    };
 
-    let cached_calibration = if let Ok(mut f) = File::open(&args.calibration_cache) {
-        println!("Found calibration file");
-        FullCameraInfo::from_be_bytes(&mut f).ok()
+    let sources = if args.source.is_empty() {
+        vec![SourceSpec::Camera(args.camera_index)]
     } else {
-        None
+        args.source
     };
+    if args.calibration_cache.len() > sources.len() {
+        return Err(anyhow!("More calibration caches than cameras"));
+    }
 
-    let mut frame = Mat::default();
-    let mut draw = if args.gui { Some(Mat::default()) } else { None };
+    // a single camera is reachable on the main port like before,
+    // several cameras get their own ports and the pings are answered for all of them,
+    // the ports are fixed so the organizer's layouts still find them after a restart
+    let single = sources.len() == 1;
+    // highgui has to run on the main thread, several cameras run on their own threads
+    if options.gui && !single {
+        return Err(anyhow!(
+            "--gui only works with a single camera, watch several with --preview-port"
+        ));
+    }
 
+    let mut cameras = vec![];
+    let mut setups = vec![];
+    for (i, source) in sources.into_iter().enumerate() {
+        let cache = args
+            .calibration_cache
+            .get(i)
+            .cloned()
+            .unwrap_or_else(|| match i {
+                0 => ".calib".to_string(),
+                i => format!(".calib-{i}"),
+            });
+
+        let cached_calibration = load_calibration(&cache, &source, &options)?;
+
+        let port = if single {
+            MAIN_PORT
+        } else {
+            (i as u16)
+                .checked_add(MAIN_PORT + 1)
+                .ok_or_else(|| anyhow!("No port left for {source}"))?
+        };
+        let socket = UdpSocket::bind(("0.0.0.0", port))?;
+        println!("{source} is on port {}", socket.local_addr()?.port());
+
+        let preview_port = args
+            .preview_port
+            .map(|p| {
+                p.checked_add(i as u16)
+                    .ok_or_else(|| anyhow!("No preview port left for {source}"))
+            })
+            .transpose()?;
+
+        cameras.push(Camera {
+            name: source.to_string(),
+            socket,
+            info: Mutex::new(HostInfo {
+                host_type: HostType::Client {
+                    calibrated: cached_calibration.is_some(),
+                },
+                host_state: HostState::Idle,
+            }),
+            identity: ClientIdentity {
+                device: source.device_name(),
+                calibration: cache.clone(),
+            },
+        });
+        setups.push(CameraSetup {
+            source,
+            calibration_cache: cache,
+            cached_calibration,
+            preview_port,
+        });
+    }
+
+    if single {
+        let (camera, setup) = (&cameras[0], setups.remove(0));
+        let res = run_camera(camera, setup, &options);
+        if let Err(e) = &res {
+            println!("{}: Stopped because: {e}", camera.name);
+        }
+        return res;
+    }
+
+    let done = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        let responder = s.spawn(|| respond_to_pings(&cameras, &done));
+
+        let handles: Vec<_> = cameras
+            .iter()
+            .zip(setups)
+            .map(|(camera, setup)| {
+                let options = &options;
+                s.spawn(move || {
+                    let res = run_camera(camera, setup, options);
+                    if let Err(e) = &res {
+                        println!("{}: Stopped because: {e}", camera.name);
+                    }
+                    res
+                })
+            })
+            .collect();
+
+        let results: Vec<Result<()>> = handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow!("Camera thread panicked")))
+            })
+            .collect();
+
+        done.store(true, Ordering::Relaxed);
+        responder
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("Ping responder panicked")))?;
+
+        results.into_iter().collect()
+    })
+}
+
+/// answers the organizer's broadcast pings from the socket of every camera
+fn respond_to_pings(cameras: &[Camera], done: &AtomicBool) -> Result<()> {
     let socket = UdpSocket::bind(("0.0.0.0", MAIN_PORT))?;
+    socket.set_read_timeout(Some(Duration::from_millis(500)))?;
     let mut buf = [0; BUF_SIZE];
 
+    while !done.load(Ordering::Relaxed) {
+        let (len, addr) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => Err(e)?,
+        };
+
+        if let Ok(Command::Ping) = buf[..len].try_into() {
+            for c in cameras {
+                c.reply_to_ping(addr)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn run_camera(camera: &Camera, setup: CameraSetup, options: &Options) -> Result<()> {
+    let socket = &camera.socket;
+    let mut cached_calibration = setup.cached_calibration;
+
+    let mut buffers = Buffers {
+        buf: [0; BUF_SIZE],
+        frame: Mat::default(),
+        preview: setup.preview_port.map(Preview::start).transpose()?,
+        draw: None,
+    };
+    if options.gui || buffers.preview.is_some() {
+        buffers.draw = Some(Mat::default());
+    }
+
     'outer_loop: loop {
-        println!("Waiting for organizer...");
+        let mut cam = setup.source.open(&options.capture)?;
+        if !cam.read(&mut buffers.frame)? {
+            return Err(anyhow!("Out of frames"));
+        }
+        let resolution = buffers.frame.size()?;
+
+        // the camera may not give the resolution it gave when the cache was checked,
+        // it's checked before the organizer is told whether the camera is calibrated
+        let calibrated_at = cached_calibration
+            .as_ref()
+            .and_then(|c| c.camera.as_ref())
+            .map(|c| c.resolution)
+            .filter(|r| *r != resolution);
+        let mut calibration = cached_calibration.as_ref().map(|c| c.calibration.clone());
+        if let Some(calibrated) = calibrated_at {
+            let message = format!(
+                "Frames are {}x{} but the camera was calibrated at {}x{}",
+                resolution.width, resolution.height, calibrated.width, calibrated.height,
+            );
+
+            match options.on_resolution_mismatch {
+                ResolutionMismatch::Recalibrate => {
+                    println!("{}: {message}, calibrate again", camera.name);
+                    discard_calibration(&setup.calibration_cache);
+                    cached_calibration = None;
+                    calibration = None;
+                }
+                ResolutionMismatch::Refuse => return Err(anyhow!(message)),
+                ResolutionMismatch::Rescale => {
+                    println!("{}: {message}, rescaling", camera.name);
+                    calibration = calibration
+                        .map(|c| {
+                            c.params
+                                .rescaled(calibrated, resolution)?
+                                .to_full(resolution)
+                        })
+                        .transpose()?;
+                }
+            }
+        }
+
+        println!("{}: Waiting for organizer...", camera.name);
         socket.set_read_timeout(None)?;
+        camera.set_state(HostState::Idle, calibration.is_some());
 
         // wait for organizer ping / start
         let organizer = loop {
-            let (len, addr) = socket.recv_from(&mut buf)?;
+            let (len, addr) = socket.recv_from(&mut buffers.buf)?;
 
-            match buf[..len].try_into() {
+            match buffers.buf[..len].try_into() {
                 Ok(Command::Start) => break addr,
-                Ok(Command::Ping) => {
-                    socket.send_to(
-                        &[TryInto::<u8>::try_into(HostInfo {
-                            host_type: HostType::Client {
-                                calibrated: cached_calibration.is_some(),
-                            },
-                            host_state: HostState::Idle,
-                        })?],
-                        addr,
-                    )?;
-                }
+                Ok(Command::Ping) => camera.reply_to_ping(addr)?,
+                Ok(Command::Identify) => camera.reply_to_identify(addr)?,
 
                 _ => continue,
             }
         };
 
-        let mut cam = VideoCapture::new(args.camera_index as i32, videoio::CAP_ANY)?;
-
         // recieve camera info and server ip
         let (config, pos) = match get_config(
-            &mut buf,
+            &mut buffers.buf,
             &organizer.ip(),
-            &mut cam,
-            &mut frame,
-            &cached_calibration,
+            cam.as_mut(),
+            &mut buffers.frame,
+            calibration.as_ref(),
         ) {
             Ok(c) => c,
             Err(e) => {
-                println!("Couldn't get config from organizer because: {e}");
+                println!(
+                    "{}: Couldn't get config from organizer because: {e}",
+                    camera.name
+                );
                 continue 'outer_loop;
             }
         };
 
+        // a new calibration replaces the cached one
+        if config.calibrated {
+            let cache = CachedCalibration {
+                camera: Some(CameraIdentity {
+                    name: setup.source.device_name(),
+                    resolution,
+                }),
+                calibration: config.calibration.clone(),
+            };
+            save_calibration(&setup.calibration_cache, &cache);
+            cached_calibration = Some(cache);
+        }
+
         socket.send_to(
             &Into::<Vec<u8>>::into(Command::Connect {
                 fov: config.calibration.horizontal_fov,
@@ -159,32 +542,122 @@ fn main() -> Result<()> {
             config.server,
         )?;
 
-        inner_loop(
-            &socket,
-            &mut cam,
-            config,
-            &mut buf,
-            &mut frame,
-            draw.as_mut(),
-        )?;
+        camera.set_state(HostState::Running, true);
+        inner_loop(camera, cam.as_mut(), config, &mut buffers, options)?;
+    }
+}
+
+/// loads the calibration cache if it was made for this camera,
+/// fails if it was made at another resolution and that isn't allowed
+fn load_calibration(
+    path: &str,
+    source: &SourceSpec,
+    options: &Options,
+) -> Result<Option<CachedCalibration>> {
+    let cached = match CachedCalibration::load(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            println!("Couldn't read calibration file `{path}` because: {e}");
+            return Ok(None);
+        }
+    };
+
+    // only open the camera if there is something to check
+    if let Some(camera) = &cached.camera {
+        let resolution = match source.resolution(&options.capture) {
+            Ok(r) => r,
+            Err(e) => {
+                println!("Couldn't check calibration file `{path}` because: {e}");
+                return Ok(None);
+            }
+        };
+
+        if camera.name != source.device_name() {
+            println!(
+                "Ignoring calibration file `{path}`, it was made for {}",
+                camera.name
+            );
+            return Ok(None);
+        }
+
+        if camera.resolution != resolution {
+            let message = format!(
+                "calibration file `{path}` was made at {}x{} but {source} gives {}x{}",
+                camera.resolution.width,
+                camera.resolution.height,
+                resolution.width,
+                resolution.height,
+            );
+
+            match options.on_resolution_mismatch {
+                ResolutionMismatch::Recalibrate => {
+                    println!("Ignoring calibration file, {message}");
+                    discard_calibration(path);
+                    return Ok(None);
+                }
+                ResolutionMismatch::Refuse => return Err(anyhow!("The {message}")),
+                // the intrinsics are scaled once the frames arrive
+                ResolutionMismatch::Rescale => println!("The {message}, rescaling"),
+            }
+        }
+    }
+
+    if cached.camera.is_none() {
+        println!(
+            "Calibration file `{path}` doesn't say which camera and resolution it was made for, \
+            make sure it's for {source}"
+        );
+    }
+
+    println!("Found calibration file `{path}` for {source}");
+    Ok(Some(cached))
+}
+
+/// moves a calibration that doesn't fit the camera anymore out of the way,
+/// so it isn't loaded again
+fn discard_calibration(path: &str) {
+    let stale = format!("{path}.stale");
+    match std::fs::rename(path, &stale) {
+        Ok(()) => println!("Moved calibration file `{path}` to `{stale}`"),
+        Err(e) => println!("Couldn't move calibration file `{path}` away because: {e}"),
+    }
+}
+
+/// saves the calibration recieved from the organizer into the cache
+fn save_calibration(path: &str, cache: &CachedCalibration) {
+    match cache.save(path) {
+        Ok(()) => println!("Saved calibration to `{path}`"),
+        Err(e) => println!("Couldn't save calibration to `{path}` because: {e}"),
     }
 }
 
 fn inner_loop(
-    socket: &UdpSocket,
-    cam: &mut VideoCapture,
+    camera: &Camera,
+    cam: &mut dyn FrameSource,
     config: Config,
-    buf: &mut [u8],
-    frame: &mut Mat,
-    mut draw: Option<&mut Mat>,
+    buffers: &mut Buffers,
+    options: &Options,
 ) -> Result<()> {
-    let mut aruco = Aruco::new(config.cube)?;
+    let socket = &camera.socket;
+    let Buffers {
+        buf,
+        frame,
+        draw,
+        preview,
+    } = buffers;
+    let mut draw = draw.as_mut();
+    let window = format!("videocap {}", camera.name);
+
+    let finder = options.detector.finder(config.cube, config.dictionary)?;
+    let mut aruco = Aruco::new(finder, &config.calibration, options.tracking)?;
+    let mut telemetry = options.telemetry_interval.map(Telemetry::new);
     println!("initalized aruco");
     socket.set_read_timeout(Some(Duration::from_millis(1)))?;
     println!("set read timeout");
 
-    if draw.is_some() {
-        highgui::named_window("videocap", highgui::WINDOW_AUTOSIZE)?;
+    if options.gui {
+        highgui::named_window(&window, highgui::WINDOW_AUTOSIZE)?;
     }
 
     let stopped_by_server;
@@ -194,16 +667,8 @@ fn inner_loop(
             Ok((len, addr)) => match buf[..len].try_into() {
                 Ok(Command::Stop) => break stopped_by_server = addr == config.server,
 
-                Ok(Command::Ping) => {
-                    socket.send_to(
-                        &[HostInfo {
-                            host_type: HostType::Client { calibrated: true },
-                            host_state: HostState::Running,
-                        }
-                        .try_into()?],
-                        addr,
-                    )?;
-                }
+                Ok(Command::Ping) => camera.reply_to_ping(addr)?,
+                Ok(Command::Identify) => camera.reply_to_identify(addr)?,
 
                 _ => (),
             },
@@ -213,30 +678,66 @@ fn inner_loop(
             Err(e) => Err(e)?,
         };
 
-        if draw.is_some() && highgui::wait_key(10)? == 113 {
+        if options.gui && highgui::wait_key(10)? == 113 {
             break stopped_by_server = false;
         }
 
-        cam.read(frame)?;
+        let capture_start = Instant::now();
+        if !cam.read(frame)? {
+            println!("{}: Out of frames", camera.name);
+            break stopped_by_server = false;
+        }
+        let capture_time = capture_start.elapsed();
 
         if let Some(draw) = draw.as_deref_mut() {
             frame.copy_to(draw)?;
         }
 
-        if let Some(data) = aruco.detect(frame, draw.as_deref_mut())? {
-            socket.send_to(
-                &Into::<Vec<u8>>::into(Command::ValueUpdate(data)),
-                config.server,
-            )?;
+        let command = if options.pose {
+            aruco
+                .detect_pose(frame, draw.as_deref_mut())?
+                .map(Command::PoseUpdate)
+        } else {
+            aruco
+                .detect(frame, draw.as_deref_mut())?
+                .map(Command::ValueUpdate)
+        };
+
+        if let (Some(preview), Some(draw)) = (preview.as_mut(), draw.as_deref()) {
+            let found = match command {
+                Some(Command::ValueUpdate(data)) => Some(data),
+                Some(Command::PoseUpdate(pose)) => Some(ClientData::from(pose)),
+                _ => None,
+            };
+            preview.update(draw, found.map(|d| d.marker_id), found.map(|d| d.bearing))?;
         }
 
-        if let Some(draw) = draw.as_deref_mut() {
-            highgui::imshow("videocap", draw)?;
+        let send_time = if let Some(command) = command {
+            let send_start = Instant::now();
+            socket.send_to(&Into::<Vec<u8>>::into(command), config.server)?;
+            Some(send_start.elapsed())
+        } else {
+            None
+        };
+
+        if let Some(telemetry) = telemetry.as_mut() {
+            telemetry.record_frame(capture_time, aruco.last_times(), send_time);
+
+            if let Some(report) = telemetry.report() {
+                socket.send_to(
+                    &Into::<Vec<u8>>::into(Command::Telemetry(report)),
+                    config.server,
+                )?;
+            }
+        }
+
+        if let Some(draw) = draw.as_deref_mut().filter(|_| options.gui) {
+            highgui::imshow(&window, draw)?;
         }
     }
 
-    if draw.is_some() {
-        highgui::destroy_all_windows()?;
+    if options.gui {
+        highgui::destroy_window(&window)?;
     }
 
     if !stopped_by_server {
@@ -246,12 +747,13 @@ fn inner_loop(
     Ok(())
 }
 
+/// `calibration` is the cached one, at the resolution of the frames
 fn get_config(
     buf: &mut [u8],
     organizer: &IpAddr,
-    cam: &mut VideoCapture,
-    mut frame: &mut Mat,
-    cached_calibration: &Option<FullCameraInfo>,
+    cam: &mut dyn FrameSource,
+    frame: &mut Mat,
+    calibration: Option<&FullCameraInfo>,
 ) -> Result<(Config, Position)> {
     let mut s = TcpStream::connect((*organizer, ORGANIZER_STARTER_PORT))?;
 
@@ -262,11 +764,20 @@ fn get_config(
             match buf[..1].try_into() {
                 Ok(Command::RequestImage) => break 'request_wait_loop,
                 Ok(Command::ImagesDone) => break 'image_loop,
+
+                // the organizer locates the camera with it
+                Ok(Command::RequestCalibration) => {
+                    if let Some(c) = calibration {
+                        s.write_all(&c.to_be_bytes())?;
+                    }
+                }
                 _ => (),
             }
         }
 
-        cam.read(&mut frame)?;
+        if !cam.read(frame)? {
+            return Err(anyhow::Error::msg("Out of frames"));
+        }
 
         let mut image_buffer = core::Vector::new();
         opencv::imgcodecs::imencode(".jpg", frame, &mut image_buffer, &core::Vector::new())?;
@@ -276,10 +787,10 @@ fn get_config(
         s.write_all(image_buffer.as_slice())?;
     }
 
-    if let Some(c) = cached_calibration {
+    if let Some(c) = calibration {
         s.write_all(c.horizontal_fov.to_be_bytes().as_slice())?;
     }
 
     // recieve camera info and server ip
-    Config::from_organizer(&mut s, cached_calibration)
+    Config::from_organizer(&mut s, calibration)
 }
@@ -0,0 +1,206 @@
+use opencv::{core, imgcodecs, prelude::*};
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Instant,
+};
+
+/// What the status endpoint reports
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Status {
+    /// smoothed frames per second of the detection loop
+    pub fps: f64,
+    pub frames: u64,
+    /// the id of the marker found on the last frame
+    pub marker_id: Option<u16>,
+    /// the bearing of the marker found on the last frame (radians)
+    pub bearing: Option<f64>,
+}
+
+impl Status {
+    fn to_json(self) -> String {
+        let optional = |v: Option<String>| v.unwrap_or_else(|| "null".to_string());
+        // json has no nan or infinity
+        let number =
+            |v: f64, precision: usize| optional(v.is_finite().then(|| format!("{v:.precision$}")));
+        format!(
+            r#"{{"fps":{},"frames":{},"detected":{},"marker_id":{},"bearing":{}}}"#,
+            number(self.fps, 2),
+            self.frames,
+            self.marker_id.is_some(),
+            optional(self.marker_id.map(|id| id.to_string())),
+            optional(self.bearing.map(|b| number(b, 5))),
+        )
+    }
+}
+
+struct Shared {
+    /// the sequence number and the jpeg of the latest frame
+    frame: Mutex<(u64, Vec<u8>)>,
+    new_frame: Condvar,
+    status: Mutex<Status>,
+    viewers: AtomicUsize,
+}
+
+/// Serves the annotated frames as an MJPEG stream on `/stream`
+/// and the detection state as JSON on `/status`
+pub struct Preview {
+    shared: Arc<Shared>,
+    status: Status,
+    last_frame: Option<Instant>,
+}
+
+impl Preview {
+    pub fn start(port: u16) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(("0.0.0.0", port))?;
+        let shared = Arc::new(Shared {
+            frame: Mutex::new((0, vec![])),
+            new_frame: Condvar::new(),
+            status: Mutex::new(Status::default()),
+            viewers: AtomicUsize::new(0),
+        });
+
+        let s = shared.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let s = s.clone();
+                thread::spawn(move || {
+                    // the viewer went away
+                    let _ = handle_connection(stream, &s);
+                });
+            }
+        });
+
+        println!("Serving preview on port {port}");
+
+        Ok(Self {
+            shared,
+            status: Status::default(),
+            last_frame: None,
+        })
+    }
+
+    /// publishes the annotated frame and what was found on it
+    pub fn update(
+        &mut self,
+        frame: &Mat,
+        marker_id: Option<u16>,
+        bearing: Option<f64>,
+    ) -> opencv::Result<()> {
+        let now = Instant::now();
+        if let Some(last) = self.last_frame.replace(now) {
+            let dt = (now - last).as_secs_f64();
+            if dt > 0. {
+                self.status.fps = if self.status.frames <= 1 {
+                    1. / dt
+                } else {
+                    0.9 * self.status.fps + 0.1 / dt
+                };
+            }
+        }
+        self.status.frames += 1;
+        self.status.marker_id = marker_id;
+        self.status.bearing = bearing;
+
+        *self.shared.status.lock().unwrap() = self.status;
+
+        // don't bother encoding if nobody is watching
+        if self.shared.viewers.load(Ordering::Relaxed) == 0 {
+            return Ok(());
+        }
+
+        let mut jpeg = core::Vector::new();
+        imgcodecs::imencode(".jpg", frame, &mut jpeg, &core::Vector::new())?;
+
+        let mut f = self.shared.frame.lock().unwrap();
+        f.0 += 1;
+        f.1 = jpeg.to_vec();
+        self.shared.new_frame.notify_all();
+
+        Ok(())
+    }
+}
+
+fn handle_connection(mut stream: TcpStream, shared: &Shared) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    match request.split_whitespace().nth(1) {
+        Some("/" | "/stream") => stream_frames(stream, shared),
+
+        Some("/status") => {
+            let body = shared.status.lock().unwrap().to_json();
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\n\
+                Content-Type: application/json\r\n\
+                Content-Length: {}\r\n\
+                Connection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+
+        _ => stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n"),
+    }
+}
+
+/// counts a viewer for as long as it lives
+struct Viewer<'a>(&'a AtomicUsize);
+
+impl<'a> Viewer<'a> {
+    fn new(viewers: &'a AtomicUsize) -> Self {
+        viewers.fetch_add(1, Ordering::Relaxed);
+        Self(viewers)
+    }
+}
+
+impl Drop for Viewer<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn stream_frames(mut stream: TcpStream, shared: &Shared) -> std::io::Result<()> {
+    stream.write_all(
+        b"HTTP/1.1 200 OK\r\n\
+        Content-Type: multipart/x-mixed-replace; boundary=frame\r\n\
+        Cache-Control: no-cache\r\n\
+        Connection: close\r\n\r\n",
+    )?;
+
+    let _viewer = Viewer::new(&shared.viewers);
+    let mut seen = shared.frame.lock().unwrap().0;
+
+    loop {
+        let jpeg = {
+            let mut f = shared.frame.lock().unwrap();
+            while f.0 == seen {
+                f = shared.new_frame.wait(f).unwrap();
+            }
+            seen = f.0;
+            f.1.clone()
+        };
+
+        write!(
+            stream,
+            "--frame\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+            jpeg.len()
+        )?;
+        stream.write_all(&jpeg)?;
+        stream.write_all(b"\r\n")?;
+    }
+}
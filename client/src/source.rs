@@ -0,0 +1,304 @@
+use anyhow::{anyhow, Result};
+use camloc_common::cv::{get_aruco_dictionary, MarkerDictionary};
+use opencv::{
+    core::{self, Rect},
+    imgcodecs, imgproc, objdetect,
+    prelude::*,
+    videoio::{self, VideoCapture},
+};
+use std::{path::PathBuf, str::FromStr};
+
+/// Something that produces frames for the detector
+pub trait FrameSource {
+    /// reads the next frame, returns false if there are no more frames
+    fn read(&mut self, frame: &mut Mat) -> opencv::Result<bool>;
+}
+
+/// A camera or a video file
+pub struct Capture {
+    cap: VideoCapture,
+}
+
+impl Capture {
+    pub fn camera(index: u16) -> opencv::Result<Self> {
+        Self::opened(
+            VideoCapture::new(index as i32, videoio::CAP_ANY)?,
+            format!("Couldn't open camera {index}"),
+        )
+    }
+
+    pub fn file(path: &str) -> opencv::Result<Self> {
+        Self::opened(
+            VideoCapture::from_file(path, videoio::CAP_ANY)?,
+            format!("Couldn't open video `{path}`"),
+        )
+    }
+
+    /// a capture that couldn't be opened would just look like it's out of frames
+    fn opened(cap: VideoCapture, error: String) -> opencv::Result<Self> {
+        if !cap.is_opened()? {
+            return Err(opencv::Error::new(core::StsError, error));
+        }
+        Ok(Self { cap })
+    }
+}
+
+/// Capture properties to set on cameras, `None` leaves the camera's default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaptureSettings {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub fps: Option<f64>,
+    /// turns auto exposure off, the unit depends on the camera
+    pub exposure: Option<f64>,
+    pub gain: Option<f64>,
+    /// turns autofocus off, the unit depends on the camera
+    pub focus: Option<f64>,
+}
+
+impl Capture {
+    /// sets the properties and warns about the ones the camera ignored
+    pub fn configure(&mut self, settings: &CaptureSettings) -> opencv::Result<()> {
+        let mut set = |name: &str, property: i32, value: f64| -> opencv::Result<()> {
+            if !self.cap.set(property, value)? {
+                println!("Camera doesn't support setting the {name}");
+            }
+            Ok(())
+        };
+
+        // the resolution is checked against the frames later
+        if let Some(width) = settings.width {
+            set("width", videoio::CAP_PROP_FRAME_WIDTH, width as f64)?;
+        }
+        if let Some(height) = settings.height {
+            set("height", videoio::CAP_PROP_FRAME_HEIGHT, height as f64)?;
+        }
+        if let Some(fps) = settings.fps {
+            set("frame rate", videoio::CAP_PROP_FPS, fps)?;
+        }
+        if let Some(exposure) = settings.exposure {
+            // 1 is manual exposure for v4l2
+            set("exposure mode", videoio::CAP_PROP_AUTO_EXPOSURE, 1.)?;
+            set("exposure", videoio::CAP_PROP_EXPOSURE, exposure)?;
+        }
+        if let Some(gain) = settings.gain {
+            set("gain", videoio::CAP_PROP_GAIN, gain)?;
+        }
+        if let Some(focus) = settings.focus {
+            set("focus mode", videoio::CAP_PROP_AUTOFOCUS, 0.)?;
+            set("focus", videoio::CAP_PROP_FOCUS, focus)?;
+        }
+
+        if let Some(fps) = settings.fps {
+            let actual = self.cap.get(videoio::CAP_PROP_FPS)?;
+            if (actual - fps).abs() > 0.5 {
+                println!("Camera runs at {actual:.1} fps instead of {fps:.1}");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FrameSource for Capture {
+    fn read(&mut self, frame: &mut Mat) -> opencv::Result<bool> {
+        self.cap.read(frame)
+    }
+}
+
+/// Images of a directory in alphabetical order
+pub struct ImageDirectory {
+    files: Vec<PathBuf>,
+    next: usize,
+}
+
+impl ImageDirectory {
+    pub fn new(dir: &str) -> std::io::Result<Self> {
+        let mut files = std::fs::read_dir(dir)?
+            .map(|e| e.map(|e| e.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        files.retain(|f| f.is_file());
+        files.sort();
+
+        Ok(Self { files, next: 0 })
+    }
+}
+
+impl FrameSource for ImageDirectory {
+    fn read(&mut self, frame: &mut Mat) -> opencv::Result<bool> {
+        while let Some(f) = self.files.get(self.next) {
+            self.next += 1;
+
+            // skip anything that isn't an image
+            let img = imgcodecs::imread(&f.to_string_lossy(), imgcodecs::IMREAD_COLOR)?;
+            if !img.empty() {
+                *frame = img;
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// A single marker sliding back and forth on a white background
+pub struct Synthetic {
+    marker: Mat,
+    size: core::Size,
+    frame_index: u64,
+}
+
+impl Synthetic {
+    const MARKER_SIZE: i32 = 120;
+
+    pub fn new(marker_id: u16, dictionary: MarkerDictionary) -> opencv::Result<Self> {
+        let mut gray = Mat::default();
+        objdetect::generate_image_marker(
+            &get_aruco_dictionary(dictionary)?,
+            marker_id as i32,
+            Self::MARKER_SIZE,
+            &mut gray,
+            1,
+        )?;
+
+        let mut marker = Mat::default();
+        imgproc::cvt_color(&gray, &mut marker, imgproc::COLOR_GRAY2BGR, 0)?;
+
+        Ok(Self {
+            marker,
+            size: core::Size::new(640, 480),
+            frame_index: 0,
+        })
+    }
+}
+
+impl FrameSource for Synthetic {
+    fn read(&mut self, frame: &mut Mat) -> opencv::Result<bool> {
+        *frame = Mat::new_size_with_default(self.size, core::CV_8UC3, core::Scalar::all(255.))?;
+
+        let t = self.frame_index as f64 * 0.05;
+        self.frame_index += 1;
+
+        // keep a white margin around the marker
+        let margin = Self::MARKER_SIZE;
+        let free_width = (self.size.width - 2 * margin - Self::MARKER_SIZE) as f64;
+        let x = margin + (0.5 * free_width * (1. + t.sin())) as i32;
+        let y = (self.size.height - Self::MARKER_SIZE) / 2;
+
+        let mut roi = Mat::roi(frame, Rect::new(x, y, Self::MARKER_SIZE, Self::MARKER_SIZE))?;
+        self.marker.copy_to(&mut roi)?;
+
+        Ok(true)
+    }
+}
+
+/// Describes where frames come from
+///
+/// - `camera:<index>`
+/// - `video:<file>`
+/// - `images:<directory>`
+/// - `synthetic:<marker id>[:<dictionary>]`
+#[derive(Debug, Clone)]
+pub enum SourceSpec {
+    Camera(u16),
+    Video(String),
+    Images(String),
+    Synthetic(u16, MarkerDictionary),
+}
+
+impl SourceSpec {
+    /// opens the source, the settings only apply to cameras
+    pub fn open(&self, settings: &CaptureSettings) -> Result<Box<dyn FrameSource>> {
+        Ok(match self {
+            SourceSpec::Camera(index) => {
+                let mut cap = Capture::camera(*index)?;
+                cap.configure(settings)?;
+                Box::new(cap)
+            }
+            SourceSpec::Video(path) => Box::new(Capture::file(path)?),
+            SourceSpec::Images(dir) => Box::new(ImageDirectory::new(dir)?),
+            SourceSpec::Synthetic(marker_id, dictionary) => {
+                Box::new(Synthetic::new(*marker_id, *dictionary)?)
+            }
+        })
+    }
+
+    /// Identifies the device: the source and, for cameras on linux,
+    /// the device's name and usb serial if it reports one.
+    /// Two cameras of the same model without serials plugged in at the same index
+    /// still can't be told apart.
+    pub fn device_name(&self) -> String {
+        let SourceSpec::Camera(index) = self else {
+            return self.to_string();
+        };
+
+        let device = PathBuf::from(format!("/sys/class/video4linux/video{index}"));
+        let read = |path: PathBuf| {
+            std::fs::read_to_string(path)
+                .ok()
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+        };
+
+        let details: Vec<_> = [
+            read(device.join("name")),
+            // the video device is an interface of the usb device
+            read(device.join("device/../serial")).map(|s| format!("serial {s}")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        if details.is_empty() {
+            self.to_string()
+        } else {
+            format!("{self} ({})", details.join(", "))
+        }
+    }
+
+    /// opens the source and reads a frame to find out its resolution
+    pub fn resolution(&self, settings: &CaptureSettings) -> Result<core::Size> {
+        let mut frame = Mat::default();
+        if !self.open(settings)?.read(&mut frame)? {
+            return Err(anyhow!("`{self}` has no frames"));
+        }
+        Ok(frame.size()?)
+    }
+}
+
+impl FromStr for SourceSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, arg) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected <kind>:<argument>, got `{s}`"))?;
+
+        Ok(match kind {
+            "camera" => SourceSpec::Camera(arg.parse()?),
+            "video" => SourceSpec::Video(arg.to_string()),
+            "images" => SourceSpec::Images(arg.to_string()),
+            "synthetic" => {
+                let (marker_id, dictionary) = match arg.split_once(':') {
+                    Some((id, dictionary)) => (id, dictionary.parse().map_err(|e| anyhow!("{e}"))?),
+                    None => (arg, MarkerDictionary::default()),
+                };
+                SourceSpec::Synthetic(marker_id.parse()?, dictionary)
+            }
+            _ => return Err(anyhow!("Unknown source kind `{kind}`")),
+        })
+    }
+}
+
+impl std::fmt::Display for SourceSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SourceSpec::Camera(index) => write!(f, "camera:{index}"),
+            SourceSpec::Video(path) => write!(f, "video:{path}"),
+            SourceSpec::Images(dir) => write!(f, "images:{dir}"),
+            SourceSpec::Synthetic(marker_id, dictionary) => {
+                write!(f, "synthetic:{marker_id}:{dictionary}")
+            }
+        }
+    }
+}
@@ -0,0 +1,74 @@
+use camloc_common::hosts::{ClientTelemetry, TimingStats};
+use std::time::{Duration, Instant};
+
+use crate::aruco::StepTimes;
+
+/// Collects the timings of the detection loop and summarizes them periodically
+pub struct Telemetry {
+    interval: Duration,
+    period_start: Instant,
+    frames: u32,
+    capture: Vec<f64>,
+    detection: Vec<f64>,
+    tracking: Vec<f64>,
+    send: Vec<f64>,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.
+}
+
+impl Telemetry {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            period_start: Instant::now(),
+            frames: 0,
+            capture: vec![],
+            detection: vec![],
+            tracking: vec![],
+            send: vec![],
+        }
+    }
+
+    pub fn record_frame(&mut self, capture: Duration, steps: StepTimes, send: Option<Duration>) {
+        self.frames += 1;
+        self.capture.push(millis(capture));
+
+        if let Some(d) = steps.detection {
+            self.detection.push(millis(d));
+        }
+        if let Some(t) = steps.tracking {
+            self.tracking.push(millis(t));
+        }
+        if let Some(s) = send {
+            self.send.push(millis(s));
+        }
+    }
+
+    /// summarizes the period if it's over and starts the next one
+    pub fn report(&mut self) -> Option<ClientTelemetry> {
+        let elapsed = self.period_start.elapsed();
+        if elapsed < self.interval {
+            return None;
+        }
+
+        let telemetry = ClientTelemetry {
+            frames: self.frames,
+            fps: self.frames as f64 / elapsed.as_secs_f64(),
+            capture: TimingStats::from_samples(&mut self.capture),
+            detection: TimingStats::from_samples(&mut self.detection),
+            tracking: TimingStats::from_samples(&mut self.tracking),
+            send: TimingStats::from_samples(&mut self.send),
+        };
+
+        self.period_start = Instant::now();
+        self.frames = 0;
+        self.capture.clear();
+        self.detection.clear();
+        self.tracking.clear();
+        self.send.clear();
+
+        Some(telemetry)
+    }
+}
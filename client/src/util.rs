@@ -1,4 +1,5 @@
-use opencv::{self, core, imgproc, prelude::*, types};
+use camloc_common::cv::CameraParams;
+use opencv::{self, calib3d, core, imgproc, prelude::*, types};
 
 #[allow(dead_code)]
 pub enum Color {
@@ -84,20 +85,57 @@ pub fn draw_x(frame: &mut Mat, p: core::Point2i, c: Color) -> opencv::Result<()>
     )
 }
 
-pub fn avg_corners(bounding: &types::VectorOfPoint2f) -> core::Point2i {
-    core::Point2i::new(
-        (bounding.iter().map(|s| s.x).sum::<f32>() / bounding.len() as f32).round() as i32,
-        (bounding.iter().map(|s| s.y).sum::<f32>() / bounding.len() as f32).round() as i32,
-    )
+pub fn round_point(p: core::Point2f) -> core::Point2i {
+    core::Point2i::new(p.x.round() as i32, p.y.round() as i32)
+}
+
+/// average length of the left and right edges of the marker
+pub fn marker_height(bounding: &types::VectorOfPoint2f) -> opencv::Result<f64> {
+    // corners are in clockwise order starting from the top left one
+    let edge = |a: usize, b: usize| -> opencv::Result<f64> {
+        let (a, b) = (bounding.get(a)?, bounding.get(b)?);
+        Ok(((a.x - b.x) as f64).hypot((a.y - b.y) as f64))
+    };
+
+    Ok(0.5 * (edge(0, 3)? + edge(1, 2)?))
+}
+
+/// angle subtended by a length on the normalized image plane (radians)
+pub fn angular_size(size: f64) -> f64 {
+    2. * (0.5 * size).atan()
+}
+
+/// removes lens distortion, the results are normalized image coordinates
+/// (the ray through the point is `(x, y, 1)` in the camera frame)
+pub fn undistort_points(
+    points: &types::VectorOfPoint2f,
+    params: &CameraParams,
+) -> opencv::Result<types::VectorOfPoint2f> {
+    let mut undistorted = types::VectorOfPoint2f::new();
+    calib3d::undistort_points(
+        points,
+        &mut undistorted,
+        &params.camera_matrix,
+        &params.dist_coeffs,
+        &core::no_array(),
+        &core::no_array(),
+    )?;
+    Ok(undistorted)
+}
+
+/// horizontal angle between the optical axis and a normalized image point
+/// (radians, positive to the left)
+pub fn bearing(point: core::Point2f) -> f64 {
+    -(point.x as f64).atan()
 }
 
 pub fn bounding_to_rect(bounding: &types::VectorOfPoint2f, offset: i32) -> core::Rect2i {
     let (mut sx, mut sy, mut ex, mut ey) = (i32::MAX, i32::MAX, i32::MIN, i32::MIN);
     for p in bounding {
-        sx = sx.min(p.x as i32);
-        sy = sy.min(p.y as i32);
-        ex = ex.max(p.x as i32);
-        ey = ey.max(p.y as i32);
+        sx = sx.min(p.x.floor() as i32);
+        sy = sy.min(p.y.floor() as i32);
+        ex = ex.max(p.x.ceil() as i32);
+        ey = ey.max(p.y.ceil() as i32);
     }
 
     core::Rect2i::new(
@@ -108,10 +146,25 @@ pub fn bounding_to_rect(bounding: &types::VectorOfPoint2f, offset: i32) -> core:
     )
 }
 
-pub fn rect(frame: &mut Mat, rect: core::Rect2i, c: Color) -> opencv::Result<()> {
-    imgproc::rectangle(frame, rect, get_color(&c), 2, imgproc::LINE_8, 0)
+/// grows the rectangle by `ratio` of its size on every side
+pub fn grow_rect(r: core::Rect2i, ratio: f64) -> core::Rect2i {
+    let (dx, dy) = (
+        (r.width as f64 * ratio) as i32,
+        (r.height as f64 * ratio) as i32,
+    );
+    core::Rect2i::new(r.x - dx, r.y - dy, r.width + 2 * dx, r.height + 2 * dy)
 }
 
-pub fn relative_x(frame: &Mat, point: core::Point2i) -> opencv::Result<f64> {
-    Ok(point.x as f64 / frame.size()?.width as f64)
+/// the part of the rectangle that's inside an image of the given size
+pub fn clamp_rect(r: core::Rect2i, size: core::Size) -> core::Rect2i {
+    let (sx, sy) = (r.x.max(0), r.y.max(0));
+    let (ex, ey) = (
+        (r.x + r.width).min(size.width),
+        (r.y + r.height).min(size.height),
+    );
+    core::Rect2i::new(sx, sy, (ex - sx).max(0), (ey - sy).max(0))
+}
+
+pub fn rect(frame: &mut Mat, rect: core::Rect2i, c: Color) -> opencv::Result<()> {
+    imgproc::rectangle(frame, rect, get_color(&c), 2, imgproc::LINE_8, 0)
 }
@@ -0,0 +1,100 @@
+//! A configless client like a phone app would be, see `protocol.txt`.
+//! It waits for the organizer to start it, then sends a marker
+//! sweeping across its field of view until it's stopped.
+//!
+//! `cargo run --example configless_client -- [fov in degrees] [marker id]`
+
+use camloc_common::{
+    hosts::{constants::MAIN_PORT, ClientData, Command, HostInfo, HostState, HostType},
+    Position,
+};
+use std::{
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr, UdpSocket},
+    time::{Duration, Instant},
+};
+
+const SEND_INTERVAL: Duration = Duration::from_millis(50);
+
+fn main() -> std::io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let fov = args
+        .next()
+        .map(|a| a.parse::<f64>().expect("invalid fov"))
+        .unwrap_or(60.)
+        .to_radians();
+    let marker_id = args
+        .next()
+        .map(|a| a.parse::<u16>().expect("invalid marker id"))
+        .unwrap_or(0);
+
+    let sock = UdpSocket::bind(("0.0.0.0", MAIN_PORT))?;
+    sock.set_read_timeout(Some(SEND_INTERVAL))?;
+
+    let mut buf = [0; 64];
+    let mut server: Option<SocketAddr> = None;
+    let start = Instant::now();
+
+    println!("Waiting for organizer...");
+
+    loop {
+        match sock.recv_from(&mut buf) {
+            Ok((len, addr)) => match buf[..len].try_into() {
+                Ok(Command::Ping) => {
+                    let info = HostInfo {
+                        host_type: HostType::ConfiglessClient,
+                        host_state: if server.is_some() {
+                            HostState::Running
+                        } else {
+                            HostState::Idle
+                        },
+                    };
+                    sock.send_to(&[info.try_into().unwrap()], addr)?;
+                }
+
+                Ok(Command::StartConfigless { ip }) if server.is_none() => {
+                    let Ok(ip) = ip.parse::<IpAddr>() else {
+                        println!("Invalid server ip: {ip}");
+                        continue;
+                    };
+                    let s = SocketAddr::new(ip, MAIN_PORT);
+
+                    // the organizer places the camera later
+                    let connect: Vec<u8> = Command::Connect {
+                        position: Position::new(0., 0., 0.),
+                        fov,
+                    }
+                    .into();
+                    sock.send_to(&connect, s)?;
+                    sock.send_to(&connect, addr)?;
+
+                    println!("Connected to {s}");
+                    server = Some(s);
+                }
+
+                Ok(Command::Stop) => {
+                    if let Some(s) = server.take() {
+                        sock.send_to(&Into::<Vec<u8>>::into(Command::ClientDisconnect), s)?;
+                        println!("Stopped, waiting for organizer...");
+                    }
+                }
+
+                _ => (),
+            },
+
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => (),
+            Err(e) => return Err(e),
+        }
+
+        if let Some(s) = server {
+            // across 80% of the field of view and back, every 10 seconds
+            let t = start.elapsed().as_secs_f64();
+            let bearing = 0.4 * fov * (t * std::f64::consts::TAU / 10.).sin();
+
+            sock.send_to(
+                &Into::<Vec<u8>>::into(Command::ValueUpdate(ClientData::new(marker_id, bearing))),
+                s,
+            )?;
+        }
+    }
+}
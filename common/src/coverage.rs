@@ -0,0 +1,163 @@
+use crate::PlacedCamera;
+use std::f64::consts::{PI, TAU};
+use thiserror::Error as ThisError;
+
+/// How well a point of the arena can be triangulated
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CoverageCell {
+    /// The center of the cell
+    pub x: f64,
+    pub y: f64,
+    /// Number of cameras that see the point
+    pub cameras: usize,
+    /// Expected position error for a bearing error of 1 radian
+    /// (geometric dilution of precision),
+    /// `None` if no two cameras that see the point are far enough apart to triangulate it
+    pub gdop: Option<f64>,
+}
+
+/// A grid of `CoverageCell`s over the arena, row by row starting at the smallest `y`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageMap {
+    /// The corner of the grid with the smallest `x` and `y`
+    pub origin: (f64, f64),
+    pub cell_size: f64,
+    pub columns: usize,
+    pub rows: usize,
+    /// `None` outside the arena
+    pub cells: Vec<Option<CoverageCell>>,
+}
+
+#[derive(Debug, ThisError, PartialEq)]
+pub enum CoverageError {
+    #[error("the cell size has to be a positive number, not {0}")]
+    InvalidCellSize(f64),
+}
+
+impl CoverageMap {
+    /// `arena` is the corners of the arena in order,
+    /// `min_camera_angle_diff` is the server's (see `with_min_camera_angle_diff`)
+    pub fn new(
+        cameras: &[PlacedCamera],
+        arena: &[(f64, f64)],
+        min_camera_angle_diff: f64,
+        cell_size: f64,
+    ) -> Result<Self, CoverageError> {
+        if !(cell_size.is_finite() && cell_size > 0.) {
+            return Err(CoverageError::InvalidCellSize(cell_size));
+        }
+
+        let (mut min, mut max) = (
+            (f64::INFINITY, f64::INFINITY),
+            (-f64::INFINITY, -f64::INFINITY),
+        );
+        for &(x, y) in arena {
+            min = (min.0.min(x), min.1.min(y));
+            max = (max.0.max(x), max.1.max(y));
+        }
+
+        let (columns, rows) = if arena.is_empty() {
+            (0, 0)
+        } else {
+            (
+                ((max.0 - min.0) / cell_size).ceil() as usize,
+                ((max.1 - min.1) / cell_size).ceil() as usize,
+            )
+        };
+
+        let cells = (0..rows)
+            .flat_map(|r| (0..columns).map(move |c| (r, c)))
+            .map(|(r, c)| {
+                let x = min.0 + (c as f64 + 0.5) * cell_size;
+                let y = min.1 + (r as f64 + 0.5) * cell_size;
+
+                inside(arena, x, y).then(|| cell(cameras, min_camera_angle_diff, x, y))
+            })
+            .collect();
+
+        Ok(Self {
+            origin: min,
+            cell_size,
+            columns,
+            rows,
+            cells,
+        })
+    }
+
+    pub fn get(&self, column: usize, row: usize) -> Option<&CoverageCell> {
+        self.cells.get(row * self.columns + column)?.as_ref()
+    }
+
+    /// One line per cell inside the arena: `x,y,cameras,gdop`,
+    /// `gdop` is empty if the cell can't be triangulated
+    pub fn write_csv(&self, mut w: impl std::io::Write) -> std::io::Result<()> {
+        writeln!(w, "x,y,cameras,gdop")?;
+        for c in self.cells.iter().flatten() {
+            write!(w, "{},{},{},", c.x, c.y, c.cameras)?;
+            match c.gdop {
+                Some(gdop) => writeln!(w, "{gdop}")?,
+                None => writeln!(w)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn cell(cameras: &[PlacedCamera], min_camera_angle_diff: f64, x: f64, y: f64) -> CoverageCell {
+    let seeing: Vec<_> = cameras.iter().filter(|c| sees(c, x, y)).collect();
+
+    // like the server, only pairs of cameras not too close to parallel are triangulated
+    let usable: Vec<_> = seeing
+        .iter()
+        .filter(|a| {
+            seeing.iter().any(|b| {
+                let diff = (a.position.rotation - b.position.rotation).rem_euclid(PI);
+                diff.min(PI - diff) >= min_camera_angle_diff
+            })
+        })
+        .collect();
+
+    // the bearings' jacobian is [-dy, dx] / d^2, gdop is sqrt(trace((H^T H)^-1))
+    let (mut xx, mut xy, mut yy) = (0., 0., 0.);
+    for c in &usable {
+        let (dx, dy) = (x - c.position.x, y - c.position.y);
+        let d2 = dx * dx + dy * dy;
+        // a camera has no bearing to itself
+        if d2 <= f64::EPSILON {
+            continue;
+        }
+        let (hx, hy) = (-dy / d2, dx / d2);
+        xx += hx * hx;
+        xy += hx * hy;
+        yy += hy * hy;
+    }
+    let determinant = xx * yy - xy * xy;
+
+    CoverageCell {
+        x,
+        y,
+        cameras: seeing.len(),
+        gdop: (usable.len() >= 2 && determinant > f64::EPSILON)
+            .then(|| ((xx + yy) / determinant).sqrt()),
+    }
+}
+
+/// whether the point is in the camera's horizontal fov
+fn sees(camera: &PlacedCamera, x: f64, y: f64) -> bool {
+    let p = camera.position;
+    let angle = (y - p.y).atan2(x - p.x) - p.rotation;
+    let angle = (angle + PI).rem_euclid(TAU) - PI;
+    angle.abs() <= 0.5 * camera.fov
+}
+
+/// whether the point is inside the polygon (even-odd rule)
+pub(crate) fn inside(polygon: &[(f64, f64)], x: f64, y: f64) -> bool {
+    let mut inside = false;
+    for (i, &(ax, ay)) in polygon.iter().enumerate() {
+        let (bx, by) = polygon[(i + 1) % polygon.len()];
+        if (ay > y) != (by > y) && x < ax + (y - ay) / (by - ay) * (bx - ax) {
+            inside = !inside;
+        }
+    }
+    inside
+}
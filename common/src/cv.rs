@@ -1,25 +1,216 @@
 use std::mem::size_of;
 
+use crate::{
+    coverage::CoverageMap,
+    pose::{Pose3, Quaternion},
+    Position,
+};
 use opencv::{
     aruco::calibrate_camera_charuco,
-    calib3d::get_optimal_new_camera_matrix,
+    calib3d::{self, get_optimal_new_camera_matrix},
     core::{self, FileStorage},
-    highgui,
+    highgui, imgproc,
     objdetect::{self, CharucoBoard, CharucoDetector, CharucoParameters},
     prelude::*,
     types, Result,
 };
 
-pub fn get_aruco_dictionary() -> Result<objdetect::Dictionary> {
-    objdetect::get_predefined_dictionary(objdetect::PredefinedDictionaryType::DICT_4X4_50)
+/// The predefined marker dictionaries by name, their index is their opencv value
+const DICTIONARIES: [(&str, objdetect::PredefinedDictionaryType); 22] = {
+    use objdetect::PredefinedDictionaryType::*;
+    [
+        ("4x4_50", DICT_4X4_50),
+        ("4x4_100", DICT_4X4_100),
+        ("4x4_250", DICT_4X4_250),
+        ("4x4_1000", DICT_4X4_1000),
+        ("5x5_50", DICT_5X5_50),
+        ("5x5_100", DICT_5X5_100),
+        ("5x5_250", DICT_5X5_250),
+        ("5x5_1000", DICT_5X5_1000),
+        ("6x6_50", DICT_6X6_50),
+        ("6x6_100", DICT_6X6_100),
+        ("6x6_250", DICT_6X6_250),
+        ("6x6_1000", DICT_6X6_1000),
+        ("7x7_50", DICT_7X7_50),
+        ("7x7_100", DICT_7X7_100),
+        ("7x7_250", DICT_7X7_250),
+        ("7x7_1000", DICT_7X7_1000),
+        ("aruco_original", DICT_ARUCO_ORIGINAL),
+        ("apriltag_16h5", DICT_APRILTAG_16h5),
+        ("apriltag_25h9", DICT_APRILTAG_25h9),
+        ("apriltag_36h10", DICT_APRILTAG_36h10),
+        ("apriltag_36h11", DICT_APRILTAG_36h11),
+        ("aruco_mip_36h12", DICT_ARUCO_MIP_36h12),
+    ]
+};
+
+/// One of opencv's predefined marker dictionaries
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MarkerDictionary(u8);
+
+impl MarkerDictionary {
+    pub const ARUCO_4X4_50: MarkerDictionary = MarkerDictionary(0);
+    pub const APRILTAG_36H11: MarkerDictionary = MarkerDictionary(20);
+
+    pub fn name(&self) -> &'static str {
+        DICTIONARIES[self.0 as usize].0
+    }
+
+    pub fn names() -> impl Iterator<Item = &'static str> {
+        DICTIONARIES.iter().map(|(n, _)| *n)
+    }
+
+    /// Whether this is one of the AprilTag families
+    pub fn is_apriltag(&self) -> bool {
+        self.name().starts_with("apriltag")
+    }
+}
+
+impl Default for MarkerDictionary {
+    fn default() -> Self {
+        Self::ARUCO_4X4_50
+    }
+}
+
+impl From<MarkerDictionary> for u8 {
+    fn from(d: MarkerDictionary) -> Self {
+        d.0
+    }
+}
+
+impl TryFrom<u8> for MarkerDictionary {
+    type Error = ();
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        if (v as usize) < DICTIONARIES.len() {
+            Ok(MarkerDictionary(v))
+        } else {
+            Err(())
+        }
+    }
+}
+
+impl std::str::FromStr for MarkerDictionary {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        let s = s.strip_prefix("dict_").unwrap_or(&s);
+
+        DICTIONARIES
+            .iter()
+            .position(|(n, _)| *n == s)
+            .map(|i| MarkerDictionary(i as u8))
+            .ok_or_else(|| {
+                format!(
+                    "Unknown dictionary `{s}`, expected one of: {}",
+                    Self::names().collect::<Vec<_>>().join(", ")
+                )
+            })
+    }
+}
+
+impl std::fmt::Display for MarkerDictionary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The marker dictionary and the printed dimensions of the charuco board
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoardConfig {
+    pub dictionary: MarkerDictionary,
+    /// side length of a chessboard square (meters)
+    pub square_length: f32,
+    /// side length of a marker inside a square (meters)
+    pub marker_length: f32,
 }
 
-pub fn generate_board(width: u8, height: u8) -> Result<CharucoBoard> {
+impl Default for BoardConfig {
+    fn default() -> Self {
+        Self {
+            dictionary: MarkerDictionary::default(),
+            square_length: 0.04,
+            marker_length: 0.02,
+        }
+    }
+}
+
+impl BoardConfig {
+    pub fn to_be_bytes(&self) -> [u8; 9] {
+        let mut b = [0; 9];
+        b[0] = self.dictionary.into();
+        b[1..5].copy_from_slice(&self.square_length.to_be_bytes());
+        b[5..].copy_from_slice(&self.marker_length.to_be_bytes());
+        b
+    }
+
+    pub fn from_be_bytes(r: &mut impl std::io::Read) -> Result<Self, std::io::Error> {
+        use std::io::{Error, ErrorKind};
+        let mut buf = [0; 9];
+        r.read_exact(&mut buf)?;
+
+        Ok(Self {
+            dictionary: buf[0]
+                .try_into()
+                .map_err(|_| Error::from(ErrorKind::InvalidData))?,
+            square_length: f32::from_be_bytes(buf[1..5].try_into().unwrap()),
+            marker_length: f32::from_be_bytes(buf[5..].try_into().unwrap()),
+        })
+    }
+}
+
+pub fn get_aruco_dictionary(dictionary: MarkerDictionary) -> Result<objdetect::Dictionary> {
+    objdetect::get_predefined_dictionary(DICTIONARIES[dictionary.0 as usize].1)
+}
+
+/// marker detector parameters with sub-pixel corner refinement
+pub fn get_detector_parameters() -> Result<objdetect::DetectorParameters> {
+    let mut params = objdetect::DetectorParameters::default()?;
+    params.set_corner_refinement_method(objdetect::CornerRefineMethod::CORNER_REFINE_SUBPIX as i32);
+    Ok(params)
+}
+
+/// marker detector parameters for apriltag families,
+/// tuned for small and slightly blurred tags
+pub fn get_apriltag_parameters() -> Result<objdetect::DetectorParameters> {
+    let mut params = objdetect::DetectorParameters::default()?;
+    params
+        .set_corner_refinement_method(objdetect::CornerRefineMethod::CORNER_REFINE_APRILTAG as i32);
+    // don't downscale, far away tags are only a few pixels wide
+    params.set_april_tag_quad_decimate(1.);
+    // smooth the noise of motion blurred frames before finding the quads
+    params.set_april_tag_quad_sigma(0.8);
+    Ok(params)
+}
+
+/// The center of a marker as the intersection of its diagonals,
+/// which (unlike the average of the corners) is also correct under perspective
+pub fn marker_center(corners: &types::VectorOfPoint2f) -> Result<core::Point2f> {
+    let [a, b, c, d] = [0, 1, 2, 3].map(|i| corners.get(i));
+    let (a, b, c, d) = (a?, b?, c?, d?);
+
+    // a + t * (c - a) = b + u * (d - b)
+    let (r, s) = (c - a, d - b);
+    let denominator = r.x * s.y - r.y * s.x;
+
+    if denominator.abs() < f32::EPSILON {
+        return Ok(core::Point2f::new(
+            0.25 * (a.x + b.x + c.x + d.x),
+            0.25 * (a.y + b.y + c.y + d.y),
+        ));
+    }
+
+    let t = ((b.x - a.x) * s.y - (b.y - a.y) * s.x) / denominator;
+    Ok(core::Point2f::new(a.x + t * r.x, a.y + t * r.y))
+}
+
+pub fn generate_board(width: u8, height: u8, config: &BoardConfig) -> Result<CharucoBoard> {
     CharucoBoard::new(
         core::Size::new(width as i32, height as i32),
-        0.04,
-        0.02,
-        &get_aruco_dictionary()?,
+        config.square_length,
+        config.marker_length,
+        &get_aruco_dictionary(config.dictionary)?,
         &core::no_array(),
     )
 }
@@ -30,7 +221,7 @@ pub fn find_board(
     include_markers: bool,
 ) -> Result<Option<FoundBoard>> {
     let marker_detector = objdetect::ArucoDetector::new(
-        &get_aruco_dictionary()?,
+        &board.get_dictionary()?,
         &objdetect::DetectorParameters::default()?,
         objdetect::RefineParameters {
             min_rep_distance: 0.5,
@@ -133,6 +324,50 @@ pub fn draw_charuco_board(image: &mut Mat, board: &FoundBoard) -> Result<()> {
     Ok(())
 }
 
+/// The coverage map as an image with `scale` pixels per cell and `y` pointing up.
+/// The error of triangulated cells goes from blue (0) to red (`max_gdop` or more),
+/// cells only one camera sees are gray, the ones no camera sees are black
+pub fn draw_coverage(map: &CoverageMap, max_gdop: f64, scale: i32) -> Result<Mat> {
+    let (rows, columns) = (map.rows as i32, map.columns as i32);
+
+    let mut values = vec![0u8; map.rows * map.columns];
+    for (v, c) in values.iter_mut().zip(&map.cells) {
+        if let Some(gdop) = c.and_then(|c| c.gdop) {
+            *v = (255. * (gdop / max_gdop).min(1.)) as u8;
+        }
+    }
+    let values = Mat::from_slice_rows_cols(&values, map.rows, map.columns)?;
+
+    let mut colored = Mat::default();
+    imgproc::apply_color_map(&values, &mut colored, imgproc::COLORMAP_JET)?;
+
+    for row in 0..rows {
+        for column in 0..columns {
+            let gray = match map.get(column as usize, row as usize) {
+                Some(c) if c.gdop.is_some() => continue,
+                Some(c) if c.cameras > 0 => 128,
+                _ => 0,
+            };
+            *colored.at_2d_mut::<core::Vec3b>(row, column)? = core::Vec3b::from_array([gray; 3]);
+        }
+    }
+
+    // the first row is the smallest y
+    let mut flipped = Mat::default();
+    core::flip(&colored, &mut flipped, 0)?;
+
+    let mut scaled = Mat::default();
+    imgproc::resize(
+        &flipped,
+        &mut scaled,
+        core::Size::default(),
+        scale as f64,
+        scale as f64,
+        imgproc::INTER_NEAREST,
+    )?;
+    Ok(scaled)
+}
+
 pub fn calibrate(
     board: &CharucoBoard,
     images: &[Mat],
@@ -190,6 +425,83 @@ pub fn calibrate(
     .to_full(image_size)
 }
 
+/// A charuco board at a known place, to locate cameras from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ReferenceBoard {
+    /// squares along the board
+    pub width: u8,
+    pub height: u8,
+    /// World from board, the board's origin is its top left corner,
+    /// x points right, y down along the board and z into it
+    pub pose: Pose3,
+}
+
+impl ReferenceBoard {
+    /// A board lying face up on the ground, with its top left corner at `corner`
+    /// and its top edge pointing in the direction of `corner.rotation`
+    pub fn on_ground(width: u8, height: u8, corner: Position) -> Self {
+        Self {
+            width,
+            height,
+            pose: Pose3::from(corner)
+                * Pose3::new(
+                    [0.; 3],
+                    Quaternion::from_axis_angle([1., 0., 0.], std::f64::consts::PI),
+                ),
+        }
+    }
+
+    pub fn generate(&self, config: &BoardConfig) -> Result<CharucoBoard> {
+        generate_board(self.width, self.height, config)
+    }
+
+    /// The pose of the camera (x forward, y left, z up) in the world,
+    /// `None` if too few corners of the board were found
+    pub fn locate_camera(
+        &self,
+        board: &CharucoBoard,
+        found: &FoundBoard,
+        params: &CameraParams,
+    ) -> Result<Option<Pose3>> {
+        // the corners of a single row are on a line, they don't give a pose
+        if found.ids.len() < 6 {
+            return Ok(None);
+        }
+
+        let mut object_points = types::VectorOfPoint3f::new();
+        let mut image_points = types::VectorOfPoint2f::new();
+        board.match_image_points(
+            &found.corners,
+            &found.ids,
+            &mut object_points,
+            &mut image_points,
+        )?;
+
+        let (mut rvec, mut tvec) = (Mat::default(), Mat::default());
+        if !calib3d::solve_pnp(
+            &object_points,
+            &image_points,
+            &params.camera_matrix,
+            &params.dist_coeffs,
+            &mut rvec,
+            &mut tvec,
+            false,
+            calib3d::SOLVEPNP_ITERATIVE,
+        )? {
+            return Ok(None);
+        }
+
+        let v = |m: &Mat| -> Result<[f64; 3]> {
+            Ok([*m.at::<f64>(0)?, *m.at::<f64>(1)?, *m.at::<f64>(2)?])
+        };
+        let opencv_from_board = Pose3::new(v(&tvec)?, Quaternion::from_rotation_vector(v(&rvec)?));
+
+        Ok(Some(
+            self.pose * opencv_from_board.inverse() * Pose3::camera_from_opencv().inverse(),
+        ))
+    }
+}
+
 #[derive(Debug)]
 pub struct CameraParams {
     /// f64 | 3x3
@@ -222,6 +534,31 @@ impl CameraParams {
     }
 }
 
+impl CameraParams {
+    /// The intrinsics of the same camera at a different resolution,
+    /// assumes the image was scaled, not cropped
+    pub fn rescaled(&self, from: core::Size, to: core::Size) -> Result<CameraParams> {
+        let sx = to.width as f64 / from.width as f64;
+        let sy = to.height as f64 / from.height as f64;
+
+        let scale = |m: &Mat| -> Result<Mat> {
+            let mut m = m.clone();
+            *m.at_2d_mut::<f64>(0, 0)? *= sx;
+            *m.at_2d_mut::<f64>(0, 2)? *= sx;
+            *m.at_2d_mut::<f64>(1, 1)? *= sy;
+            *m.at_2d_mut::<f64>(1, 2)? *= sy;
+            Ok(m)
+        };
+
+        Ok(CameraParams {
+            camera_matrix: scale(&self.camera_matrix)?,
+            optimal_matrix: scale(&self.optimal_matrix)?,
+            // distortion is relative to the focal length, it doesn't change
+            dist_coeffs: self.dist_coeffs.clone(),
+        })
+    }
+}
+
 impl Clone for CameraParams {
     fn clone(&self) -> Self {
         Self {
@@ -351,6 +688,101 @@ impl FullCameraInfo {
     }
 }
 
+/// The camera a calibration was made for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CameraIdentity {
+    /// Identifies the device, e.g. its source and model,
+    /// the client only checks that it's the same
+    pub name: String,
+    pub resolution: core::Size,
+}
+
+/// A calibration saved on disk together with the camera it belongs to
+#[derive(Debug, Clone)]
+pub struct CachedCalibration {
+    /// `None` for calibrations saved without a header (e.g. by the calibration cli)
+    pub camera: Option<CameraIdentity>,
+    pub calibration: FullCameraInfo,
+}
+
+impl CachedCalibration {
+    const MAGIC: &'static [u8; 4] = b"CLCC";
+    const VERSION: u8 = 1;
+
+    /// Whether the calibration can be used for `camera`,
+    /// calibrations without a header are always accepted
+    pub fn is_valid_for(&self, camera: &CameraIdentity) -> bool {
+        !matches!(&self.camera, Some(c) if c != camera)
+    }
+
+    /// Writes the cache into a temporary file first,
+    /// so an interrupted save never leaves a broken cache behind
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+
+        let mut bytes = vec![];
+        if let Some(camera) = &self.camera {
+            let name = camera.name.as_bytes();
+
+            bytes.extend_from_slice(Self::MAGIC);
+            bytes.push(Self::VERSION);
+            bytes.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            bytes.extend_from_slice(name);
+            bytes.extend_from_slice(&(camera.resolution.width as u32).to_be_bytes());
+            bytes.extend_from_slice(&(camera.resolution.height as u32).to_be_bytes());
+        }
+        bytes.extend(self.calibration.to_be_bytes());
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    pub fn load(path: impl AsRef<std::path::Path>) -> std::io::Result<Self> {
+        use std::io::{Error, ErrorKind, Read};
+
+        let bytes = std::fs::read(path)?;
+        let mut r = bytes.as_slice();
+
+        let Some(rest) = r.strip_prefix(Self::MAGIC) else {
+            return Ok(Self {
+                camera: None,
+                calibration: FullCameraInfo::from_be_bytes(&mut r)?,
+            });
+        };
+        r = rest;
+
+        let mut buf = [0; 4];
+        r.read_exact(&mut buf[..1])?;
+        if buf[0] != Self::VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "unknown calibration cache version",
+            ));
+        }
+
+        r.read_exact(&mut buf[..2])?;
+        let mut name = vec![0; u16::from_be_bytes([buf[0], buf[1]]) as usize];
+        r.read_exact(&mut name)?;
+        let name = String::from_utf8(name).map_err(|_| Error::from(ErrorKind::InvalidData))?;
+
+        r.read_exact(&mut buf)?;
+        let width = u32::from_be_bytes(buf) as i32;
+        r.read_exact(&mut buf)?;
+        let height = u32::from_be_bytes(buf) as i32;
+
+        Ok(Self {
+            camera: Some(CameraIdentity {
+                name,
+                resolution: core::Size::new(width, height),
+            }),
+            calibration: FullCameraInfo::from_be_bytes(&mut r)?,
+        })
+    }
+}
+
 #[derive(Debug)]
 pub struct FullCameraInfo {
     pub params: CameraParams,
@@ -376,3 +808,14 @@ pub struct FoundMarkers {
     corners: types::VectorOfVectorOfPoint2f,
     ids: types::VectorOfi32,
 }
+
+impl FoundBoard {
+    /// The charuco corners found (in image coordinates)
+    pub fn corners(&self) -> &types::VectorOfPoint2f {
+        &self.corners
+    }
+
+    pub fn ids(&self) -> &types::VectorOfi32 {
+        &self.ids
+    }
+}
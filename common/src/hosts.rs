@@ -1,4 +1,4 @@
-use crate::Position;
+use crate::{Pose3, Position};
 
 #[allow(clippy::unusual_byte_groupings)]
 pub mod constants {
@@ -118,6 +118,13 @@ pub enum HostState {
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Command<'a> {
     Ping,
+    /// Asks a client which camera answers from the address
+    Identify,
+    /// The answer to `Identify`, see `ClientIdentity`
+    Identity {
+        device: &'a str,
+        calibration: &'a str,
+    },
 
     Connect {
         position: Position,
@@ -127,26 +134,37 @@ pub enum Command<'a> {
 
     Start,
     StartServer {
-        cube: [u8; 4],
+        cube: [u16; 4],
     },
+    /// Starts a client that doesn't need the organizer's config,
+    /// see `protocol.txt` for the whole flow
     StartConfigless {
+        /// ip of the server
         ip: &'a str,
     },
     Stop,
 
     RequestImage,
+    /// Asks a calibrated client for its calibration while starting it
+    RequestCalibration,
     ImagesDone,
 
     ValueUpdate(ClientData),
+    PoseUpdate(MarkerPose),
+    Telemetry(ClientTelemetry),
     InfoUpdate {
+        /// `ip:port` of the client (just the ip for older organizers)
         client_ip: &'a str,
         position: Position,
         fov: Option<f64>,
+        mount: Option<Pose3>,
     },
 }
 
 impl Command<'_> {
     pub const PING: u8 = 0x0b;
+    pub const IDENTIFY: u8 = 0x1a;
+    pub const IDENTITY: u8 = 0x1b;
     pub const CONNECT: u8 = 0xcc;
     pub const CLIENT_DISCONNECT: u8 = 0xdc;
     pub const START: u8 = 0x60;
@@ -154,8 +172,11 @@ impl Command<'_> {
     pub const START_CONFIGLESS: u8 = 0x6c;
     pub const STOP: u8 = 0xcd;
     pub const REQUEST_IMAGE: u8 = 0x17;
+    pub const REQUEST_CALIBRATION: u8 = 0x1c;
     pub const IMAGES_DONE: u8 = 0x1d;
     pub const VALUE_UPDATE: u8 = 0x21;
+    pub const POSE_UPDATE: u8 = 0x22;
+    pub const TELEMETRY: u8 = 0x23;
     pub const INFO_UPDATE: u8 = 0x1f;
 }
 
@@ -164,6 +185,19 @@ impl From<Command<'_>> for Vec<u8> {
         match value {
             Command::Ping => vec![Command::PING],
 
+            Command::Identify => vec![Command::IDENTIFY],
+            Command::Identity {
+                device,
+                calibration,
+            } => [
+                Command::IDENTITY.to_be_bytes().as_slice(),
+                (device.len() as u16).to_be_bytes().as_slice(),
+                device.as_bytes(),
+                (calibration.len() as u16).to_be_bytes().as_slice(),
+                calibration.as_bytes(),
+            ]
+            .concat(),
+
             Command::Connect { position, fov } => [
                 Command::CONNECT.to_be_bytes().as_slice(),
                 position.to_be_bytes().as_slice(),
@@ -176,7 +210,7 @@ impl From<Command<'_>> for Vec<u8> {
             Command::Start => vec![Command::START],
             Command::StartServer { cube } => [
                 Command::START_SERVER.to_be_bytes().as_slice(),
-                cube.map(u8::to_be).as_slice(),
+                cube.map(u16::to_be_bytes).concat().as_slice(),
             ]
             .concat(),
 
@@ -191,15 +225,45 @@ impl From<Command<'_>> for Vec<u8> {
 
             Command::RequestImage => vec![Command::REQUEST_IMAGE],
 
+            Command::RequestCalibration => vec![Command::REQUEST_CALIBRATION],
+
             Command::ImagesDone => vec![Command::IMAGES_DONE],
 
             Command::ValueUpdate(ClientData {
                 marker_id,
-                x_position: value,
+                bearing,
+                marker_height,
+            }) => {
+                let c = Command::VALUE_UPDATE.to_be_bytes();
+                let m = marker_id.to_be_bytes();
+                let x = bearing.to_be_bytes();
+                let f = if marker_height.is_some() { 1u8 } else { 0u8 }.to_be_bytes();
+
+                let mut v = vec![c.as_slice(), m.as_slice(), x.as_slice(), f.as_slice()];
+
+                let marker_height = marker_height.map(f64::to_be_bytes);
+                if let Some(h) = &marker_height {
+                    v.push(h);
+                }
+
+                v.concat()
+            }
+
+            Command::PoseUpdate(MarkerPose {
+                marker_id,
+                rvec,
+                tvec,
             }) => [
-                Command::VALUE_UPDATE.to_be_bytes().as_slice(),
+                Command::POSE_UPDATE.to_be_bytes().as_slice(),
                 marker_id.to_be_bytes().as_slice(),
-                value.to_be_bytes().as_slice(),
+                rvec.map(f64::to_be_bytes).concat().as_slice(),
+                tvec.map(f64::to_be_bytes).concat().as_slice(),
+            ]
+            .concat(),
+
+            Command::Telemetry(telemetry) => [
+                Command::TELEMETRY.to_be_bytes().as_slice(),
+                telemetry.to_be_bytes().as_slice(),
             ]
             .concat(),
 
@@ -207,17 +271,19 @@ impl From<Command<'_>> for Vec<u8> {
                 client_ip,
                 position,
                 fov,
+                mount,
             } => {
+                let c = Command::INFO_UPDATE.to_be_bytes();
                 let ip = client_ip.as_bytes();
                 let ip_len = (ip.len() as u16).to_be_bytes();
-                let c = Command::INFO_UPDATE.to_be_bytes();
                 let p = position.to_be_bytes();
                 let f = if fov.is_some() { 1u8 } else { 0u8 }.to_be_bytes();
+                let m = if mount.is_some() { 1u8 } else { 0u8 }.to_be_bytes();
 
                 let mut v = vec![
+                    c.as_slice(),
                     ip_len.as_slice(),
                     ip,
-                    c.as_slice(),
                     p.as_slice(),
                     f.as_slice(),
                 ];
@@ -227,6 +293,12 @@ impl From<Command<'_>> for Vec<u8> {
                     v.push(fov);
                 }
 
+                v.push(m.as_slice());
+                let mount = mount.map(|m| m.to_be_bytes());
+                if let Some(mount) = &mount {
+                    v.push(mount);
+                }
+
                 v.concat()
             }
         }
@@ -249,16 +321,57 @@ impl<'a> TryFrom<&'a [u8]> for Command<'a> {
         (|| {
             Some(match cmd {
                 Command::PING => Command::Ping,
+                Command::IDENTIFY => Command::Identify,
                 Command::START => Command::Start,
                 Command::STOP => Command::Stop,
                 Command::REQUEST_IMAGE => Command::RequestImage,
+                Command::REQUEST_CALIBRATION => Command::RequestCalibration,
                 Command::IMAGES_DONE => Command::ImagesDone,
                 Command::CLIENT_DISCONNECT => Command::ClientDisconnect,
 
-                Command::VALUE_UPDATE => Command::ValueUpdate(ClientData {
-                    marker_id: u8::from_be(*buf.first()?),
-                    x_position: f64::from_be_bytes(buf.get(1..9)?.try_into().ok()?),
-                }),
+                Command::VALUE_UPDATE => {
+                    // older clients send a u8 id, they're told apart by the length
+                    let (marker_id, buf) = if matches!(buf.len(), 11 | 19) {
+                        (marker_id(buf)?, &buf[2..])
+                    } else {
+                        (*buf.first()? as u16, &buf[1..])
+                    };
+
+                    Command::ValueUpdate(ClientData {
+                        marker_id,
+                        bearing: f64::from_be_bytes(buf.get(..8)?.try_into().ok()?),
+                        // older clients don't send the marker height at all
+                        marker_height: if buf.get(8) == Some(&1) {
+                            Some(f64::from_be_bytes(buf.get(9..17)?.try_into().ok()?))
+                        } else {
+                            None
+                        },
+                    })
+                }
+
+                Command::POSE_UPDATE => {
+                    // older clients send a u8 id
+                    let (marker_id, buf) = if buf.len() >= 50 {
+                        (marker_id(buf)?, &buf[2..])
+                    } else {
+                        (*buf.first()? as u16, &buf[1..])
+                    };
+                    let f = |i: usize| -> Option<f64> {
+                        Some(f64::from_be_bytes(
+                            buf.get(i * 8..(i + 1) * 8)?.try_into().ok()?,
+                        ))
+                    };
+
+                    Command::PoseUpdate(MarkerPose {
+                        marker_id,
+                        rvec: [f(0)?, f(1)?, f(2)?],
+                        tvec: [f(3)?, f(4)?, f(5)?],
+                    })
+                }
+
+                Command::TELEMETRY => Command::Telemetry(ClientTelemetry::from_be_bytes(
+                    buf.get(..ClientTelemetry::SIZE)?.try_into().ok()?,
+                )),
 
                 Command::CONNECT => Command::Connect {
                     position: Position::from_be_bytes(&buf.get(..24)?.try_into().ok()?),
@@ -267,17 +380,43 @@ impl<'a> TryFrom<&'a [u8]> for Command<'a> {
 
                 Command::INFO_UPDATE => {
                     let ip_len = u16::from_be_bytes(buf.get(..2)?.try_into().ok()?) as usize;
-                    let client_ip = std::str::from_utf8(buf.get(2..2 + ip_len)?).unwrap();
-                    let position = Position::from_be_bytes(&buf.get(..26)?.try_into().ok()?);
-                    let fov = if *buf.get(26)? == 1 {
-                        Some(f64::from_be_bytes(buf.get(27..35)?.try_into().ok()?))
+                    let client_ip = std::str::from_utf8(buf.get(2..2 + ip_len)?).ok()?;
+                    let buf = &buf[2 + ip_len..];
+
+                    let position = Position::from_be_bytes(buf.get(..24)?.try_into().ok()?);
+                    let (fov, buf) = if *buf.get(24)? == 1 {
+                        (
+                            Some(f64::from_be_bytes(buf.get(25..33)?.try_into().ok()?)),
+                            &buf[33..],
+                        )
+                    } else {
+                        (None, &buf[25..])
+                    };
+                    // older organizers don't send the mount pose at all
+                    let mount = if buf.first() == Some(&1) {
+                        Some(Pose3::from_be_bytes(buf.get(1..57)?.try_into().ok()?))
                     } else {
                         None
                     };
+
                     Command::InfoUpdate {
                         client_ip,
                         position,
                         fov,
+                        mount,
+                    }
+                }
+
+                Command::IDENTITY => {
+                    let device_len = u16::from_be_bytes(buf.get(..2)?.try_into().ok()?) as usize;
+                    let device = std::str::from_utf8(buf.get(2..2 + device_len)?).ok()?;
+                    let buf = &buf[2 + device_len..];
+                    let calibration_len =
+                        u16::from_be_bytes(buf.get(..2)?.try_into().ok()?) as usize;
+
+                    Command::Identity {
+                        device,
+                        calibration: std::str::from_utf8(buf.get(2..2 + calibration_len)?).ok()?,
                     }
                 }
 
@@ -290,7 +429,12 @@ impl<'a> TryFrom<&'a [u8]> for Command<'a> {
                 }
 
                 Command::START_SERVER => Command::StartServer {
-                    cube: buf.get(..4)?.try_into().ok()?,
+                    // older organizers send u8 ids
+                    cube: if buf.len() >= 8 {
+                        [0, 1, 2, 3].map(|i| u16::from_be_bytes([buf[2 * i], buf[2 * i + 1]]))
+                    } else {
+                        <[u8; 4]>::try_from(buf.get(..4)?).ok()?.map(u16::from)
+                    },
                 },
 
                 _ => return None,
@@ -300,6 +444,10 @@ impl<'a> TryFrom<&'a [u8]> for Command<'a> {
     }
 }
 
+fn marker_id(buf: &[u8]) -> Option<u16> {
+    Some(u16::from_be_bytes(buf.get(..2)?.try_into().ok()?))
+}
+
 impl<'a> TryFrom<&'a mut [u8]> for Command<'a> {
     type Error = ();
 
@@ -310,15 +458,178 @@ impl<'a> TryFrom<&'a mut [u8]> for Command<'a> {
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub struct ClientData {
-    pub x_position: f64,
-    pub marker_id: u8,
+    /// Horizontal angle between the optical axis and the marker
+    /// (**in radians**, positive to the left)
+    pub bearing: f64,
+    pub marker_id: u16,
+    /// Apparent (angular) height of the marker (**in radians**), if known
+    pub marker_height: Option<f64>,
 }
 
 impl ClientData {
-    pub fn new(marker_id: u8, x_position: f64) -> ClientData {
+    pub fn new(marker_id: u16, bearing: f64) -> ClientData {
+        Self {
+            bearing,
+            marker_id,
+            marker_height: None,
+        }
+    }
+
+    pub fn with_marker_height(mut self, marker_height: f64) -> ClientData {
+        self.marker_height = Some(marker_height);
+        self
+    }
+}
+
+/// The pose of a single marker relative to the camera that saw it,
+/// in OpenCV's camera frame (x right, y down, z forward)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MarkerPose {
+    pub marker_id: u16,
+    /// Rotation of the marker as a Rodrigues vector
+    pub rvec: [f64; 3],
+    /// Position of the marker center in **marker side lengths**
+    pub tvec: [f64; 3],
+}
+
+impl MarkerPose {
+    pub fn new(marker_id: u16, rvec: [f64; 3], tvec: [f64; 3]) -> MarkerPose {
         Self {
-            x_position,
             marker_id,
+            rvec,
+            tvec,
+        }
+    }
+}
+
+impl From<MarkerPose> for ClientData {
+    fn from(pose: MarkerPose) -> Self {
+        let [x, y, z] = pose.tvec;
+        let distance = (x * x + y * y + z * z).sqrt();
+
+        // the marker is one unit tall
+        ClientData::new(pose.marker_id, (-x).atan2(z))
+            .with_marker_height(2. * (0.5 / distance).atan())
+    }
+}
+
+/// Durations of one processing step of a client (**in milliseconds**)
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TimingStats {
+    pub mean: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+impl TimingStats {
+    const SIZE: usize = 4 * 8;
+
+    /// Summarizes the samples, sorts them in the process
+    pub fn from_samples(samples: &mut [f64]) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+
+        samples.sort_by(f64::total_cmp);
+        let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+
+        Self {
+            mean: samples.iter().sum::<f64>() / samples.len() as f64,
+            p50: percentile(0.5),
+            p95: percentile(0.95),
+            max: samples[samples.len() - 1],
+        }
+    }
+
+    fn to_be_bytes(self) -> [u8; Self::SIZE] {
+        let mut b = [0; Self::SIZE];
+        for (c, v) in b
+            .chunks_mut(8)
+            .zip([self.mean, self.p50, self.p95, self.max])
+        {
+            c.copy_from_slice(&v.to_be_bytes());
         }
+        b
+    }
+
+    fn from_be_bytes(b: &[u8; Self::SIZE]) -> Self {
+        let f = |i: usize| f64::from_be_bytes(b[i * 8..(i + 1) * 8].try_into().unwrap());
+        Self {
+            mean: f(0),
+            p50: f(1),
+            p95: f(2),
+            max: f(3),
+        }
+    }
+}
+
+impl std::fmt::Display for TimingStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1}/{:.1}/{:.1}/{:.1}ms",
+            self.mean, self.p50, self.p95, self.max
+        )
+    }
+}
+
+/// Performance of a client over its last reporting period
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientTelemetry {
+    /// Frames processed during the period
+    pub frames: u32,
+    pub fps: f64,
+    /// Reading a frame from the source
+    pub capture: TimingStats,
+    /// Running the marker detector (frames where it ran)
+    pub detection: TimingStats,
+    /// Running the tracker (frames where it ran)
+    pub tracking: TimingStats,
+    /// Sending the update to the server
+    pub send: TimingStats,
+}
+
+impl ClientTelemetry {
+    pub const SIZE: usize = 4 + 8 + 4 * TimingStats::SIZE;
+
+    pub fn to_be_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0; Self::SIZE];
+        b[..4].copy_from_slice(&self.frames.to_be_bytes());
+        b[4..12].copy_from_slice(&self.fps.to_be_bytes());
+
+        let stats = [self.capture, self.detection, self.tracking, self.send];
+        for (c, s) in b[12..].chunks_mut(TimingStats::SIZE).zip(stats) {
+            c.copy_from_slice(&s.to_be_bytes());
+        }
+        b
+    }
+
+    pub fn from_be_bytes(b: &[u8; Self::SIZE]) -> Self {
+        let stats = |i: usize| {
+            let start = 12 + i * TimingStats::SIZE;
+            TimingStats::from_be_bytes(b[start..start + TimingStats::SIZE].try_into().unwrap())
+        };
+
+        Self {
+            frames: u32::from_be_bytes(b[..4].try_into().unwrap()),
+            fps: f64::from_be_bytes(b[4..12].try_into().unwrap()),
+            capture: stats(0),
+            detection: stats(1),
+            tracking: stats(2),
+            send: stats(3),
+        }
+    }
+}
+
+impl std::fmt::Display for ClientTelemetry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.1}fps ({} frames) capture {} detection {} tracking {} send {}",
+            self.fps, self.frames, self.capture, self.detection, self.tracking, self.send
+        )
     }
 }
@@ -0,0 +1,89 @@
+use crate::PlacedCamera;
+use std::net::{IpAddr, SocketAddr};
+
+/// A whole deployment, so it doesn't have to be set up again after a restart
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Layout {
+    /// The marker ids on the cube (counterclockwise)
+    pub cube: [u16; 4],
+    pub server: Option<IpAddr>,
+    pub clients: Vec<ClientLayout>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientLayout {
+    /// `ip:port` of the client's camera, the server tells cameras apart by it
+    pub address: SocketAddr,
+    /// `None` for clients that don't tell (e.g. configless clients),
+    /// they're only found by their address
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub identity: Option<ClientIdentity>,
+    pub camera: PlacedCamera,
+}
+
+/// What a client tells about one of its cameras when asked with `IDENTIFY`
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ClientIdentity {
+    /// The name of the camera (see `cv::CameraIdentity`),
+    /// unlike the port it doesn't depend on the order the client was given its cameras in
+    pub device: String,
+    /// The client's calibration cache of the camera
+    pub calibration: String,
+}
+
+impl Layout {
+    pub fn new(cube: [u16; 4]) -> Self {
+        Self {
+            cube,
+            server: None,
+            clients: vec![],
+        }
+    }
+
+    /// The client with the camera's device if both know it, the one at the address otherwise
+    pub fn client(
+        &self,
+        address: SocketAddr,
+        identity: Option<&ClientIdentity>,
+    ) -> Option<&ClientLayout> {
+        let device = identity.map(|i| &i.device);
+        let same_device = self
+            .clients
+            .iter()
+            .find(|c| device.is_some() && c.identity.as_ref().map(|i| &i.device) == device);
+
+        same_device.or_else(|| {
+            self.clients
+                .iter()
+                .find(|c| c.address == address && (c.identity.is_none() || device.is_none()))
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+#[derive(Debug, thiserror::Error)]
+pub enum LayoutError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid layout: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+#[cfg(feature = "serde")]
+impl Layout {
+    /// Reads a layout saved with `save`
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, LayoutError> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    /// Writes the layout as json
+    pub fn save(&self, path: impl AsRef<std::path::Path>) -> Result<(), LayoutError> {
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
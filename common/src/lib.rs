@@ -8,10 +8,15 @@ use thiserror::Error as ThisError;
 #[cfg(feature = "cv")]
 pub mod cv;
 
+pub mod coverage;
 pub mod hosts;
+pub mod layout;
+pub mod pose;
 pub mod position;
 
-pub use position::Position;
+pub use layout::{ClientIdentity, ClientLayout, Layout};
+pub use pose::Pose3;
+pub use position::{PlacedCamera, Position};
 
 pub trait Lerp {
     fn lerp(start: &Self, end: &Self, t: f64) -> Self;
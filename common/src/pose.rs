@@ -0,0 +1,281 @@
+use crate::Position;
+
+/// A unit quaternion representing a rotation
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Quaternion {
+    pub w: f64,
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+}
+
+impl Quaternion {
+    pub const IDENTITY: Quaternion = Quaternion::new(1., 0., 0., 0.);
+
+    pub const fn new(w: f64, x: f64, y: f64, z: f64) -> Self {
+        Quaternion { w, x, y, z }
+    }
+
+    /// Rotation by `angle` radians around `axis`
+    pub fn from_axis_angle(axis: [f64; 3], angle: f64) -> Self {
+        let [x, y, z] = axis;
+        let len = (x * x + y * y + z * z).sqrt();
+        if len < f64::EPSILON {
+            return Self::IDENTITY;
+        }
+
+        let (s, c) = (0.5 * angle).sin_cos();
+        let s = s / len;
+        Quaternion::new(c, x * s, y * s, z * s)
+    }
+
+    /// Rotation from a Rodrigues vector (axis scaled by the angle)
+    pub fn from_rotation_vector(v: [f64; 3]) -> Self {
+        let angle = (v[0] * v[0] + v[1] * v[1] + v[2] * v[2]).sqrt();
+        Self::from_axis_angle(v, angle)
+    }
+
+    /// Rotation from z-y-x euler angles (**in radians**), `z` points up
+    pub fn from_yaw_pitch_roll(yaw: f64, pitch: f64, roll: f64) -> Self {
+        Self::from_axis_angle([0., 0., 1.], yaw)
+            * Self::from_axis_angle([0., 1., 0.], pitch)
+            * Self::from_axis_angle([1., 0., 0.], roll)
+    }
+
+    /// Z-y-x euler angles (**in radians**) as `(yaw, pitch, roll)`
+    pub fn to_yaw_pitch_roll(&self) -> (f64, f64, f64) {
+        let m = self.to_rotation_matrix();
+        (
+            m[1][0].atan2(m[0][0]),
+            (-m[2][0]).clamp(-1., 1.).asin(),
+            m[2][1].atan2(m[2][2]),
+        )
+    }
+
+    pub fn from_rotation_matrix(m: [[f64; 3]; 3]) -> Self {
+        let trace = m[0][0] + m[1][1] + m[2][2];
+
+        let q = if trace > 0. {
+            let s = 2. * (trace + 1.).sqrt();
+            Quaternion::new(
+                0.25 * s,
+                (m[2][1] - m[1][2]) / s,
+                (m[0][2] - m[2][0]) / s,
+                (m[1][0] - m[0][1]) / s,
+            )
+        } else if m[0][0] > m[1][1] && m[0][0] > m[2][2] {
+            let s = 2. * (1. + m[0][0] - m[1][1] - m[2][2]).sqrt();
+            Quaternion::new(
+                (m[2][1] - m[1][2]) / s,
+                0.25 * s,
+                (m[0][1] + m[1][0]) / s,
+                (m[0][2] + m[2][0]) / s,
+            )
+        } else if m[1][1] > m[2][2] {
+            let s = 2. * (1. + m[1][1] - m[0][0] - m[2][2]).sqrt();
+            Quaternion::new(
+                (m[0][2] - m[2][0]) / s,
+                (m[0][1] + m[1][0]) / s,
+                0.25 * s,
+                (m[1][2] + m[2][1]) / s,
+            )
+        } else {
+            let s = 2. * (1. + m[2][2] - m[0][0] - m[1][1]).sqrt();
+            Quaternion::new(
+                (m[1][0] - m[0][1]) / s,
+                (m[0][2] + m[2][0]) / s,
+                (m[1][2] + m[2][1]) / s,
+                0.25 * s,
+            )
+        };
+
+        q.normalized()
+    }
+
+    pub fn to_rotation_matrix(&self) -> [[f64; 3]; 3] {
+        let Quaternion { w, x, y, z } = *self;
+        [
+            [
+                1. - 2. * (y * y + z * z),
+                2. * (x * y - w * z),
+                2. * (x * z + w * y),
+            ],
+            [
+                2. * (x * y + w * z),
+                1. - 2. * (x * x + z * z),
+                2. * (y * z - w * x),
+            ],
+            [
+                2. * (x * z - w * y),
+                2. * (y * z + w * x),
+                1. - 2. * (x * x + y * y),
+            ],
+        ]
+    }
+
+    pub fn dot(&self, other: &Self) -> f64 {
+        self.w * other.w + self.x * other.x + self.y * other.y + self.z * other.z
+    }
+
+    pub fn normalized(&self) -> Self {
+        let len = self.dot(self).sqrt();
+        Quaternion::new(self.w / len, self.x / len, self.y / len, self.z / len)
+    }
+
+    /// The inverse rotation (for unit quaternions)
+    pub fn conjugate(&self) -> Self {
+        Quaternion::new(self.w, -self.x, -self.y, -self.z)
+    }
+
+    pub fn rotate(&self, v: [f64; 3]) -> [f64; 3] {
+        let p = *self * Quaternion::new(0., v[0], v[1], v[2]) * self.conjugate();
+        [p.x, p.y, p.z]
+    }
+
+    /// Normalized average of rotations that are close to each other
+    pub fn average(quaternions: impl IntoIterator<Item = Self>) -> Option<Self> {
+        let mut quaternions = quaternions.into_iter();
+        let first = quaternions.next()?;
+
+        let mut sum = first;
+        for q in quaternions {
+            // q and -q are the same rotation
+            let sign = if q.dot(&first) < 0. { -1. } else { 1. };
+            sum.w += sign * q.w;
+            sum.x += sign * q.x;
+            sum.y += sign * q.y;
+            sum.z += sign * q.z;
+        }
+
+        Some(sum.normalized())
+    }
+
+    pub fn from_be_bytes(b: &[u8; 32]) -> Self {
+        let f = |i: usize| f64::from_be_bytes(b[i * 8..(i + 1) * 8].try_into().unwrap());
+        Quaternion::new(f(0), f(1), f(2), f(3))
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; 32] {
+        let mut b = [0; 32];
+        for (c, v) in b.chunks_mut(8).zip([self.w, self.x, self.y, self.z]) {
+            c.copy_from_slice(&v.to_be_bytes());
+        }
+        b
+    }
+}
+
+impl std::ops::Mul for Quaternion {
+    type Output = Self;
+
+    fn mul(self, rhs: Self) -> Self::Output {
+        Quaternion {
+            w: self.w * rhs.w - self.x * rhs.x - self.y * rhs.y - self.z * rhs.z,
+            x: self.w * rhs.x + self.x * rhs.w + self.y * rhs.z - self.z * rhs.y,
+            y: self.w * rhs.y - self.x * rhs.z + self.y * rhs.w + self.z * rhs.x,
+            z: self.w * rhs.z + self.x * rhs.y - self.y * rhs.x + self.z * rhs.w,
+        }
+    }
+}
+
+/// A position and orientation in 3D space, `z` points up
+///
+/// A `Position` is a `Pose3` on the ground plane only rotated around `z`
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Pose3 {
+    pub translation: [f64; 3],
+    pub rotation: Quaternion,
+}
+
+impl Pose3 {
+    pub const IDENTITY: Pose3 = Pose3::new([0.; 3], Quaternion::IDENTITY);
+
+    pub const fn new(translation: [f64; 3], rotation: Quaternion) -> Self {
+        Pose3 {
+            translation,
+            rotation,
+        }
+    }
+
+    /// Camera body (x forward, y left, z up) from OpenCV's camera frame (x right, y down, z forward)
+    pub fn camera_from_opencv() -> Self {
+        Pose3::new(
+            [0.; 3],
+            Quaternion::from_rotation_matrix([[0., 0., 1.], [-1., 0., 0.], [0., -1., 0.]]),
+        )
+    }
+
+    pub fn inverse(&self) -> Self {
+        let rotation = self.rotation.conjugate();
+        let [x, y, z] = rotation.rotate(self.translation);
+        Pose3::new([-x, -y, -z], rotation)
+    }
+
+    /// Maps a point from the local frame of the pose into the parent frame
+    pub fn transform_point(&self, p: [f64; 3]) -> [f64; 3] {
+        let [x, y, z] = self.rotation.rotate(p);
+        let [tx, ty, tz] = self.translation;
+        [x + tx, y + ty, z + tz]
+    }
+
+    pub fn from_be_bytes(b: &[u8; 56]) -> Self {
+        let f = |i: usize| f64::from_be_bytes(b[i * 8..(i + 1) * 8].try_into().unwrap());
+        Pose3::new(
+            [f(0), f(1), f(2)],
+            Quaternion::from_be_bytes(b[24..].try_into().unwrap()),
+        )
+    }
+
+    pub fn to_be_bytes(&self) -> [u8; 56] {
+        let mut b = [0; 56];
+        for (c, v) in b[..24].chunks_mut(8).zip(self.translation) {
+            c.copy_from_slice(&v.to_be_bytes());
+        }
+        b[24..].copy_from_slice(&self.rotation.to_be_bytes());
+        b
+    }
+}
+
+impl std::ops::Mul for Pose3 {
+    type Output = Self;
+
+    /// Composes the poses, `self` being the parent frame of `rhs`
+    fn mul(self, rhs: Self) -> Self::Output {
+        Pose3::new(
+            self.transform_point(rhs.translation),
+            (self.rotation * rhs.rotation).normalized(),
+        )
+    }
+}
+
+impl From<Position> for Pose3 {
+    fn from(p: Position) -> Self {
+        Pose3::new(
+            [p.x, p.y, 0.],
+            Quaternion::from_axis_angle([0., 0., 1.], p.rotation),
+        )
+    }
+}
+
+impl From<Pose3> for Position {
+    /// Projects the pose onto the ground plane, keeping only the yaw
+    fn from(p: Pose3) -> Self {
+        let (yaw, _, _) = p.rotation.to_yaw_pitch_roll();
+        Position::new(p.translation[0], p.translation[1], yaw)
+    }
+}
+
+impl std::fmt::Display for Pose3 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [x, y, z] = self.translation;
+        let (yaw, pitch, roll) = self.rotation.to_yaw_pitch_roll();
+        write!(
+            f,
+            "({x:.2}; {y:.2}; {z:.2}; {:.2}°; {:.2}°; {:.2}°)",
+            yaw.to_degrees(),
+            pitch.to_degrees(),
+            roll.to_degrees(),
+        )
+    }
+}
@@ -1,4 +1,6 @@
 use super::Lerp;
+use crate::pose::{Pose3, Quaternion};
+use thiserror::Error as ThisError;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
@@ -38,6 +40,60 @@ impl std::fmt::Display for Position {
     }
 }
 
+#[derive(Debug, PartialEq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PlacedCamera {
+    /// Horizontal FOV (**in radians**)
+    pub fov: f64,
+    pub position: Position,
+    /// Full 3D pose of the camera (x forward, y left, z up),
+    /// `position` is its projection onto the ground plane
+    pub mount: Option<Pose3>,
+}
+
+impl PlacedCamera {
+    pub fn new(position: Position, fov: f64) -> Self {
+        Self {
+            position,
+            fov,
+            mount: None,
+        }
+    }
+
+    pub fn mounted(mount: Pose3, fov: f64) -> Self {
+        Self {
+            position: mount.into(),
+            mount: Some(mount),
+            fov,
+        }
+    }
+
+    /// The 3D pose of the camera, level and on the ground if it has no mount pose
+    pub fn pose(&self) -> Pose3 {
+        self.mount.unwrap_or_else(|| self.position.into())
+    }
+
+    pub fn set_mount(&mut self, mount: Pose3) {
+        self.position = mount.into();
+        self.mount = Some(mount);
+    }
+
+    /// Moves the camera on the ground plane, keeping its height and tilt
+    pub fn set_position(&mut self, position: Position) {
+        if let Some(mount) = &mut self.mount {
+            let turn = Quaternion::from_axis_angle(
+                [0., 0., 1.],
+                position.rotation - self.position.rotation,
+            );
+
+            mount.translation[0] = position.x;
+            mount.translation[1] = position.y;
+            mount.rotation = (turn * mount.rotation).normalized();
+        }
+        self.position = position;
+    }
+}
+
 static CPOS: [(f64, f64); 4] = [(-1., 0.), (0., -1.), (1., 0.), (0., 1.)];
 
 pub fn calc_posotion_in_square_fov(side_length: f64, index: usize, fov: f64) -> Position {
@@ -69,6 +125,146 @@ pub fn get_camera_distance_in_square(side_length: f64, fov: f64) -> f64 {
     0.5 * side_length * (1. / (0.5 * fov).tan() + 1.)
 }
 
+/// The area the cameras have to see, centered on the origin
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Arena {
+    Circle {
+        radius: f64,
+    },
+    /// `width` along x, `height` along y
+    Rectangle {
+        width: f64,
+        height: f64,
+    },
+}
+
+impl Arena {
+    /// How far from the center a camera looking at it in the direction of `rotation`
+    /// has to be to see the whole arena
+    pub fn camera_distance(&self, rotation: f64, fov: f64) -> f64 {
+        match *self {
+            Arena::Circle { radius } => radius / (0.5 * fov).sin(),
+
+            Arena::Rectangle { width, height } => {
+                let (s, c) = rotation.sin_cos();
+                let t = (0.5 * fov).tan();
+
+                // every corner has to be inside the fov
+                [(1., 1.), (1., -1.), (-1., 1.), (-1., -1.)]
+                    .map(|(sx, sy)| {
+                        let (x, y) = (0.5 * sx * width, 0.5 * sy * height);
+                        let (forward, left) = (x * c + y * s, y * c - x * s);
+                        left.abs() / t - forward
+                    })
+                    .into_iter()
+                    .fold(0., f64::max)
+            }
+        }
+    }
+}
+
+#[derive(Debug, ThisError, PartialEq)]
+#[error("there's no camera {index} of {count}")]
+pub struct CameraIndexError {
+    pub index: usize,
+    pub count: usize,
+}
+
+/// Direction of the `index`th of `count` cameras evenly spaced around the center
+/// (counterclockwise, the first one looks along x like in a square)
+fn direction_around(index: usize, count: usize) -> Result<f64, CameraIndexError> {
+    if index >= count {
+        return Err(CameraIndexError { index, count });
+    }
+    Ok(index as f64 * std::f64::consts::TAU / count as f64)
+}
+
+/// The `index`th of `count` cameras evenly spaced around the arena,
+/// looking at its center from just far enough to see all of it
+pub fn calc_position_around(
+    arena: &Arena,
+    index: usize,
+    count: usize,
+    fov: f64,
+) -> Result<Position, CameraIndexError> {
+    let rotation = direction_around(index, count)?;
+    let distance = arena.camera_distance(rotation, fov);
+    let (s, c) = rotation.sin_cos();
+
+    Ok(Position::new(-distance * c, -distance * s, rotation))
+}
+
+#[derive(Debug, ThisError, PartialEq)]
+pub enum WallLayoutError {
+    #[error(transparent)]
+    Index(#[from] CameraIndexError),
+
+    #[error("the center of the arena isn't inside the room")]
+    OutsideRoom,
+
+    #[error(
+        "camera {index} needs to be {needed:.2} away, but its wall is only {available:.2} away"
+    )]
+    TooClose {
+        index: usize,
+        needed: f64,
+        available: f64,
+    },
+}
+
+/// The `index`th of `count` cameras on the walls of a room, evenly spaced around the arena
+/// and looking at its center. `room` is the corners of the room in order.
+pub fn calc_position_on_walls(
+    room: &[(f64, f64)],
+    arena: &Arena,
+    index: usize,
+    count: usize,
+    fov: f64,
+) -> Result<Position, WallLayoutError> {
+    let rotation = direction_around(index, count)?;
+    // a ray from outside could still hit a wall
+    if !crate::coverage::inside(room, 0., 0.) {
+        return Err(WallLayoutError::OutsideRoom);
+    }
+
+    let (s, c) = rotation.sin_cos();
+    // the camera is behind the center
+    let (dx, dy) = (-c, -s);
+
+    // the closest wall the ray from the center hits
+    let mut available = f64::INFINITY;
+    for (i, &(ax, ay)) in room.iter().enumerate() {
+        let (bx, by) = room[(i + 1) % room.len()];
+        let (ex, ey) = (bx - ax, by - ay);
+
+        // center + t * d = a + u * e
+        let denominator = dx * ey - dy * ex;
+        if denominator.abs() < f64::EPSILON {
+            continue;
+        }
+        let t = (ax * ey - ay * ex) / denominator;
+        let u = (ax * dy - ay * dx) / denominator;
+
+        if t > 0. && (0. ..=1.).contains(&u) {
+            available = available.min(t);
+        }
+    }
+    if available.is_infinite() {
+        return Err(WallLayoutError::OutsideRoom);
+    }
+
+    let needed = arena.camera_distance(rotation, fov);
+    if needed > available {
+        return Err(WallLayoutError::TooClose {
+            index,
+            needed,
+            available,
+        });
+    }
+
+    Ok(Position::new(available * dx, available * dy, rotation))
+}
+
 impl Lerp for Position {
     fn lerp(s: &Self, e: &Self, t: f64) -> Self {
         Position::new(
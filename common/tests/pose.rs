@@ -0,0 +1,126 @@
+//! Quaternion and pose math, conversions and encodings
+
+use camloc_common::{pose::Quaternion, Pose3, Position};
+use std::f64::consts::{FRAC_PI_2, PI};
+
+const EPSILON: f64 = 1e-9;
+
+fn assert_close(a: f64, b: f64) {
+    assert!((a - b).abs() < EPSILON, "{a} != {b}");
+}
+
+fn assert_vec_close(a: [f64; 3], b: [f64; 3]) {
+    for (a, b) in a.into_iter().zip(b) {
+        assert_close(a, b);
+    }
+}
+
+/// `q` and `-q` are the same rotation
+fn assert_same_rotation(a: Quaternion, b: Quaternion) {
+    assert_close(a.dot(&b).abs(), 1.);
+}
+
+fn assert_pose_close(a: Pose3, b: Pose3) {
+    assert_vec_close(a.translation, b.translation);
+    assert_same_rotation(a.rotation, b.rotation);
+}
+
+/// rotations around every axis, some past the branches of `from_rotation_matrix`
+fn rotations() -> Vec<Quaternion> {
+    let axes = [
+        [1., 0., 0.],
+        [0., 1., 0.],
+        [0., 0., 1.],
+        [1., 2., 3.],
+        [-0.5, 0.2, -1.],
+    ];
+    let angles = [0., 0.1, 1., FRAC_PI_2, 2.5, PI, -0.7];
+
+    axes.into_iter()
+        .flat_map(|axis| angles.map(|angle| Quaternion::from_axis_angle(axis, angle)))
+        .collect()
+}
+
+#[test]
+fn axis_angle_rotates_around_the_axis() {
+    let q = Quaternion::from_axis_angle([0., 0., 1.], FRAC_PI_2);
+    assert_vec_close(q.rotate([1., 0., 0.]), [0., 1., 0.]);
+    assert_vec_close(q.rotate([0., 0., 1.]), [0., 0., 1.]);
+
+    let q = Quaternion::from_rotation_vector([0., -FRAC_PI_2, 0.]);
+    assert_vec_close(q.rotate([1., 0., 0.]), [0., 0., 1.]);
+}
+
+#[test]
+fn rotation_matrix_round_trips() {
+    for q in rotations() {
+        let m = q.to_rotation_matrix();
+        assert_same_rotation(Quaternion::from_rotation_matrix(m), q);
+
+        // the matrix rotates like the quaternion
+        let v = [0.3, -1.2, 2.];
+        let rotated = [0, 1, 2].map(|r| m[r][0] * v[0] + m[r][1] * v[1] + m[r][2] * v[2]);
+        assert_vec_close(rotated, q.rotate(v));
+    }
+}
+
+#[test]
+fn yaw_pitch_roll_round_trips() {
+    for (yaw, pitch, roll) in [(0., 0., 0.), (1., 0.2, -0.3), (-2.5, -1.2, 3.)] {
+        let (y, p, r) = Quaternion::from_yaw_pitch_roll(yaw, pitch, roll).to_yaw_pitch_roll();
+        assert_close(y, yaw);
+        assert_close(p, pitch);
+        assert_close(r, roll);
+    }
+}
+
+#[test]
+fn pose_composed_with_its_inverse_is_identity() {
+    for (i, q) in rotations().into_iter().enumerate() {
+        let pose = Pose3::new([1., -2. * i as f64, 0.5], q);
+
+        assert_pose_close(pose * pose.inverse(), Pose3::IDENTITY);
+        assert_pose_close(pose.inverse() * pose, Pose3::IDENTITY);
+
+        let p = [0.4, 1., -3.];
+        assert_vec_close(pose.inverse().transform_point(pose.transform_point(p)), p);
+    }
+}
+
+#[test]
+fn position_round_trips_through_pose() {
+    let position = Position::new(1.5, -2., 2.);
+    let pose: Pose3 = position.into();
+    assert_vec_close(pose.translation, [1.5, -2., 0.]);
+
+    let back: Position = pose.into();
+    assert_close(back.x, position.x);
+    assert_close(back.y, position.y);
+    assert_close(back.rotation, position.rotation);
+}
+
+#[test]
+fn pose_projects_onto_the_ground() {
+    let pose = Pose3::new(
+        [1., 2., 3.],
+        Quaternion::from_yaw_pitch_roll(0.8, -0.4, 0.1),
+    );
+    let position: Position = pose.into();
+
+    assert_close(position.x, 1.);
+    assert_close(position.y, 2.);
+    assert_close(position.rotation, 0.8);
+}
+
+#[test]
+fn bytes_round_trip() {
+    for q in rotations() {
+        assert_eq!(Quaternion::from_be_bytes(&q.to_be_bytes()), q);
+
+        let pose = Pose3::new([1.25, -3., 1e-9], q);
+        assert_eq!(Pose3::from_be_bytes(&pose.to_be_bytes()), pose);
+    }
+
+    let position = Position::new(-1., 0.125, 3.);
+    assert_eq!(Position::from_be_bytes(&position.to_be_bytes()), position);
+}
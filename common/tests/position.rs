@@ -0,0 +1,163 @@
+//! Camera distances and placements around the arena
+
+use camloc_common::{
+    position::{
+        calc_position_around, calc_position_on_walls, get_camera_distance_in_square, Arena,
+        CameraIndexError, WallLayoutError,
+    },
+    Position,
+};
+use std::f64::consts::{FRAC_PI_2, PI};
+
+const EPSILON: f64 = 1e-9;
+
+fn assert_close(a: f64, b: f64) {
+    assert!((a - b).abs() < EPSILON, "{a} != {b}");
+}
+
+fn assert_position(p: Position, x: f64, y: f64, rotation: f64) {
+    assert_close(p.x, x);
+    assert_close(p.y, y);
+    assert_close(p.rotation, rotation);
+}
+
+#[test]
+fn circle_distance_is_the_same_from_every_direction() {
+    let arena = Arena::Circle { radius: 1. };
+    let fov = 90f64.to_radians();
+
+    for rotation in [0., 0.3, FRAC_PI_2, PI, -2.] {
+        assert_close(arena.camera_distance(rotation, fov), 2f64.sqrt());
+    }
+}
+
+#[test]
+fn square_distance_matches_the_square_setup() {
+    let arena = Arena::Rectangle {
+        width: 2.,
+        height: 2.,
+    };
+    let fov = 90f64.to_radians();
+
+    assert_close(
+        arena.camera_distance(0., fov),
+        get_camera_distance_in_square(2., fov),
+    );
+}
+
+#[test]
+fn rectangle_distance_depends_on_the_side_looked_at() {
+    let (width, height) = (4., 2.);
+    let arena = Arena::Rectangle { width, height };
+    let fov = 60f64.to_radians();
+    let t = (0.5 * fov).tan();
+
+    // the far corners have to be inside the fov
+    assert_close(
+        arena.camera_distance(0., fov),
+        0.5 * height / t + 0.5 * width,
+    );
+    assert_close(
+        arena.camera_distance(FRAC_PI_2, fov),
+        0.5 * width / t + 0.5 * height,
+    );
+}
+
+#[test]
+fn cameras_around_look_at_the_center() {
+    let arena = Arena::Circle { radius: 1. };
+    let fov = 90f64.to_radians();
+    let d = 2f64.sqrt();
+
+    let first = calc_position_around(&arena, 0, 4, fov).unwrap();
+    assert_position(first, -d, 0., 0.);
+    let second = calc_position_around(&arena, 1, 4, fov).unwrap();
+    assert_position(second, 0., -d, FRAC_PI_2);
+
+    for index in 0..5 {
+        let p = calc_position_around(&arena, index, 5, fov).unwrap();
+        assert_close((-p.y).atan2(-p.x).rem_euclid(2. * PI), p.rotation);
+        assert_close(p.x.hypot(p.y), d);
+    }
+}
+
+#[test]
+fn cameras_around_reject_invalid_indices() {
+    let arena = Arena::Circle { radius: 1. };
+
+    assert_eq!(
+        calc_position_around(&arena, 4, 4, 1.),
+        Err(CameraIndexError { index: 4, count: 4 })
+    );
+    assert_eq!(
+        calc_position_around(&arena, 0, 0, 1.),
+        Err(CameraIndexError { index: 0, count: 0 })
+    );
+}
+
+const ROOM: [(f64, f64); 4] = [(-5., -5.), (5., -5.), (5., 5.), (-5., 5.)];
+
+#[test]
+fn cameras_on_walls_are_on_the_walls() {
+    let arena = Arena::Circle { radius: 1. };
+    let fov = 90f64.to_radians();
+
+    let first = calc_position_on_walls(&ROOM, &arena, 0, 4, fov).unwrap();
+    assert_position(first, -5., 0., 0.);
+    let third = calc_position_on_walls(&ROOM, &arena, 2, 4, fov).unwrap();
+    assert_position(third, 5., 0., PI);
+
+    // in the corner
+    let corner = calc_position_on_walls(&ROOM, &arena, 1, 8, fov).unwrap();
+    assert_position(corner, -5., -5., 0.25 * PI);
+}
+
+#[test]
+fn cameras_on_walls_need_enough_room() {
+    let arena = Arena::Circle { radius: 5. };
+    let fov = 90f64.to_radians();
+
+    match calc_position_on_walls(&ROOM, &arena, 0, 4, fov) {
+        Err(WallLayoutError::TooClose {
+            index,
+            needed,
+            available,
+        }) => {
+            assert_eq!(index, 0);
+            assert_close(needed, 5. * 2f64.sqrt());
+            assert_close(available, 5.);
+        }
+        r => panic!("expected TooClose, got {r:?}"),
+    }
+}
+
+#[test]
+fn cameras_on_walls_need_the_center_in_the_room() {
+    let arena = Arena::Circle { radius: 0.1 };
+    let room = [(1., 1.), (3., 1.), (3., 3.), (1., 3.)];
+
+    // the ray of every camera misses the room or hits it from outside
+    for index in 0..8 {
+        assert_eq!(
+            calc_position_on_walls(&room, &arena, index, 8, 1.),
+            Err(WallLayoutError::OutsideRoom)
+        );
+    }
+    assert_eq!(
+        calc_position_on_walls(&[], &arena, 0, 1, 1.),
+        Err(WallLayoutError::OutsideRoom)
+    );
+}
+
+#[test]
+fn cameras_on_walls_reject_invalid_indices() {
+    let arena = Arena::Circle { radius: 1. };
+
+    assert_eq!(
+        calc_position_on_walls(&ROOM, &arena, 3, 2, 1.),
+        Err(WallLayoutError::Index(CameraIndexError {
+            index: 3,
+            count: 2
+        }))
+    );
+}
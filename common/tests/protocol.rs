@@ -0,0 +1,150 @@
+//! Encoding and decoding of the commands
+
+use camloc_common::{
+    hosts::{ClientData, Command, MarkerPose},
+    pose::Quaternion,
+    Pose3, Position,
+};
+
+fn round_trip(command: Command) {
+    let bytes: Vec<u8> = command.into();
+    assert_eq!(Command::try_from(bytes.as_slice()), Ok(command));
+}
+
+#[test]
+fn info_update_round_trips() {
+    let position = Position::new(1., -2., 0.5);
+    let mount = Pose3::new(
+        [1., -2., 1.5],
+        Quaternion::from_yaw_pitch_roll(0.5, 0.2, 0.),
+    );
+
+    for client_ip in ["192.168.1.20", "192.168.1.20:1338", ""] {
+        for fov in [None, Some(1.2)] {
+            for mount in [None, Some(mount)] {
+                round_trip(Command::InfoUpdate {
+                    client_ip,
+                    position,
+                    fov,
+                    mount,
+                });
+            }
+        }
+    }
+}
+
+#[test]
+fn info_update_without_mount_from_older_organizers() {
+    let position = Position::new(3., 4., 1.);
+    let bytes: Vec<u8> = Command::InfoUpdate {
+        client_ip: "10.0.0.2",
+        position,
+        fov: Some(1.),
+        mount: None,
+    }
+    .into();
+
+    // older organizers end the command after the fov
+    let decoded = Command::try_from(&bytes[..bytes.len() - 1]);
+    assert_eq!(
+        decoded,
+        Ok(Command::InfoUpdate {
+            client_ip: "10.0.0.2",
+            position,
+            fov: Some(1.),
+            mount: None,
+        })
+    );
+}
+
+#[test]
+fn truncated_info_update_is_rejected() {
+    let bytes: Vec<u8> = Command::InfoUpdate {
+        client_ip: "10.0.0.2",
+        position: Position::new(0., 0., 0.),
+        fov: Some(1.),
+        mount: Some(Pose3::IDENTITY),
+    }
+    .into();
+
+    for len in 1..bytes.len() - 57 {
+        assert!(
+            Command::try_from(&bytes[..len]).is_err(),
+            "decoded {len} bytes"
+        );
+    }
+    // the mount is cut short
+    assert!(Command::try_from(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[test]
+fn other_commands_round_trip() {
+    round_trip(Command::Ping);
+    round_trip(Command::Connect {
+        position: Position::new(1., 2., 3.),
+        fov: 1.1,
+    });
+    round_trip(Command::StartConfigless { ip: "10.0.0.1" });
+    round_trip(Command::Identify);
+    round_trip(Command::Identity {
+        device: "camera:0 (HD Webcam, serial 1234)",
+        calibration: ".calib",
+    });
+}
+
+#[test]
+fn marker_ids_past_255_round_trip() {
+    round_trip(Command::StartServer {
+        cube: [0, 255, 300, 586],
+    });
+    round_trip(Command::ValueUpdate(ClientData::new(300, 0.25)));
+    round_trip(Command::ValueUpdate(
+        ClientData::new(586, -0.5).with_marker_height(0.1),
+    ));
+    round_trip(Command::PoseUpdate(MarkerPose::new(
+        999,
+        [0.1, 0.2, 0.3],
+        [1., 2., 3.],
+    )));
+}
+
+#[test]
+fn u8_marker_ids_of_older_hosts_are_decoded() {
+    let bearing = 0.25f64.to_be_bytes();
+    let height = 0.1f64.to_be_bytes();
+
+    let without_flag = [&[Command::VALUE_UPDATE, 7][..], &bearing].concat();
+    let without_height = [&[Command::VALUE_UPDATE, 7][..], &bearing, &[0]].concat();
+    let with_height = [&[Command::VALUE_UPDATE, 7][..], &bearing, &[1], &height].concat();
+
+    let data = ClientData::new(7, 0.25);
+    for bytes in [without_flag, without_height] {
+        assert_eq!(
+            Command::try_from(bytes.as_slice()),
+            Ok(Command::ValueUpdate(data))
+        );
+    }
+    assert_eq!(
+        Command::try_from(with_height.as_slice()),
+        Ok(Command::ValueUpdate(data.with_marker_height(0.1)))
+    );
+
+    let pose = [
+        &[Command::POSE_UPDATE, 7][..],
+        &[0.1, 0.2, 0.3, 1., 2., 3.].map(f64::to_be_bytes).concat(),
+    ]
+    .concat();
+    assert_eq!(
+        Command::try_from(pose.as_slice()),
+        Ok(Command::PoseUpdate(MarkerPose::new(
+            7,
+            [0.1, 0.2, 0.3],
+            [1., 2., 3.]
+        )))
+    );
+
+    assert_eq!(
+        Command::try_from([Command::START_SERVER, 1, 2, 3, 4].as_slice()),
+        Ok(Command::StartServer { cube: [1, 2, 3, 4] })
+    );
+}
@@ -0,0 +1,162 @@
+//! Renders markers at known sub-pixel positions and checks that refined corners
+//! and the diagonal intersection find their centers more precisely
+//! than the rounded average of unrefined corners.
+//!
+//! `cargo test --test subpixel --features cv -- --nocapture` also prints the errors
+
+use camloc_common::cv::{
+    get_aruco_dictionary, get_detector_parameters, marker_center, MarkerDictionary,
+};
+use opencv::{
+    core::{self, Mat, Rect},
+    imgproc, objdetect,
+    prelude::*,
+    types, Result,
+};
+
+const MARKER_ID: i32 = 0;
+const MARKER_SIZE: i32 = 100;
+const QUIET_ZONE: i32 = 30;
+const SAMPLES: usize = 200;
+/// the most the refined centers may be off on average (px)
+const MAX_MEAN_ERROR: f64 = 0.25;
+
+#[test]
+fn refined_centers_are_sub_pixel() -> Result<()> {
+    let dictionary = get_aruco_dictionary(MarkerDictionary::default())?;
+    let refine = objdetect::RefineParameters {
+        min_rep_distance: 0.5,
+        error_correction_rate: 1.0,
+        check_all_orders: true,
+    };
+
+    let plain = objdetect::ArucoDetector::new(
+        &dictionary,
+        &objdetect::DetectorParameters::default()?,
+        refine,
+    )?;
+    let subpixel = objdetect::ArucoDetector::new(&dictionary, &get_detector_parameters()?, refine)?;
+
+    let patch = render_patch(&dictionary)?;
+    // the marker spans whole pixels, its edges are half a pixel outside the pixel centers
+    let patch_center = (QUIET_ZONE + MARKER_SIZE / 2) as f64 - 0.5;
+
+    let mut seed = 0x2545_f491_u64;
+    let mut random = move || {
+        seed = seed
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (seed >> 11) as f64 / (1u64 << 53) as f64
+    };
+
+    let (mut plain_errors, mut subpixel_errors) = (vec![], vec![]);
+
+    for _ in 0..SAMPLES {
+        let scale = 0.6 + 0.8 * random();
+        let angle = (random() - 0.5) * 60f64.to_radians();
+        let (tx, ty) = (150. + 300. * random(), 100. + 250. * random());
+
+        let (s, c) = angle.sin_cos();
+        let (a, b) = (scale * c, scale * s);
+        let transform = Mat::from_slice_2d(&[[a, -b, tx], [b, a, ty]])?;
+
+        let expected = (
+            a * patch_center - b * patch_center + tx,
+            b * patch_center + a * patch_center + ty,
+        );
+
+        let mut frame = Mat::default();
+        imgproc::warp_affine(
+            &patch,
+            &mut frame,
+            &transform,
+            core::Size::new(640, 480),
+            imgproc::INTER_LINEAR,
+            core::BORDER_CONSTANT,
+            core::Scalar::all(255.),
+        )?;
+
+        let error = |p: (f64, f64)| (p.0 - expected.0).hypot(p.1 - expected.1);
+
+        if let Some(corners) = detect(&plain, &frame)? {
+            // what the client used to do
+            let n = corners.len() as f32;
+            let x = (corners.iter().map(|p| p.x).sum::<f32>() / n).round();
+            let y = (corners.iter().map(|p| p.y).sum::<f32>() / n).round();
+            plain_errors.push(error((x as f64, y as f64)));
+        }
+
+        if let Some(corners) = detect(&subpixel, &frame)? {
+            let center = marker_center(&corners)?;
+            subpixel_errors.push(error((center.x as f64, center.y as f64)));
+        }
+    }
+
+    let plain = report("rounded average", &plain_errors);
+    let subpixel = report("sub-pixel diagonals", &subpixel_errors);
+
+    assert!(
+        subpixel_errors.len() >= SAMPLES * 9 / 10,
+        "only {}/{SAMPLES} markers were found with refinement",
+        subpixel_errors.len()
+    );
+    assert!(
+        subpixel < MAX_MEAN_ERROR,
+        "mean error of the refined centers is {subpixel:.3}px"
+    );
+    assert!(
+        subpixel < plain,
+        "refined centers ({subpixel:.3}px) aren't better than rounded ones ({plain:.3}px)"
+    );
+
+    Ok(())
+}
+
+/// the marker on a white patch, grayscale
+fn render_patch(dictionary: &objdetect::Dictionary) -> Result<Mat> {
+    let mut marker = Mat::default();
+    objdetect::generate_image_marker(dictionary, MARKER_ID, MARKER_SIZE, &mut marker, 1)?;
+
+    let side = MARKER_SIZE + 2 * QUIET_ZONE;
+    let patch =
+        Mat::new_rows_cols_with_default(side, side, core::CV_8UC1, core::Scalar::all(255.))?;
+
+    let mut roi = Mat::roi(
+        &patch,
+        Rect::new(QUIET_ZONE, QUIET_ZONE, MARKER_SIZE, MARKER_SIZE),
+    )?;
+    marker.copy_to(&mut roi)?;
+
+    Ok(patch)
+}
+
+fn detect(
+    detector: &objdetect::ArucoDetector,
+    frame: &Mat,
+) -> Result<Option<types::VectorOfPoint2f>> {
+    let mut corners = types::VectorOfVectorOfPoint2f::new();
+    let mut ids = types::VectorOfi32::new();
+    detector.detect_markers(frame, &mut corners, &mut ids, &mut core::no_array())?;
+
+    match ids.iter().position(|id| id == MARKER_ID) {
+        Some(i) => Ok(Some(corners.get(i)?)),
+        None => Ok(None),
+    }
+}
+
+/// prints the errors, returns the mean error
+fn report(name: &str, errors: &[f64]) -> f64 {
+    if errors.is_empty() {
+        println!("{name:<20} no detections");
+        return f64::INFINITY;
+    }
+
+    let mean = errors.iter().sum::<f64>() / errors.len() as f64;
+    let max = errors.iter().copied().fold(0., f64::max);
+
+    println!(
+        "{name:<20} detected {:>3}/{SAMPLES}, mean error {mean:.3}px, max error {max:.3}px",
+        errors.len()
+    );
+    mean
+}
@@ -1,19 +1,40 @@
 use anyhow::{anyhow, Result};
 use camloc_common::{
     choice,
-    cv::{self, display_image},
+    coverage::CoverageMap,
+    cv::{self, display_image, BoardConfig, FoundBoard, MarkerDictionary, ReferenceBoard},
     get_from_stdin,
     hosts::{HostState, HostType},
-    position::{calc_position_in_square_distance, get_camera_distance_in_square},
-    yes_no_choice, Position,
+    pose::Quaternion,
+    position::{
+        calc_position_around, calc_position_in_square_distance, calc_position_on_walls,
+        get_camera_distance_in_square, Arena,
+    },
+    yes_no_choice, Layout, Pose3, Position,
 };
 use camloc_organizer::{
-    CalibrationInterface, Host, ImageStreamInterface, Organizer, OrganizerInterface,
+    quality::{QualityGate, QualityThresholds},
+    CalibrationInterface, Host, ImageStreamInterface, LocateInterface, Organizer,
+    OrganizerInterface,
 };
+use std::cell::RefCell;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 enum SetupType {
-    Square { side_length: f64 },
+    Square {
+        side_length: f64,
+    },
+    /// evenly spaced around the arena
+    Around {
+        arena: Arena,
+        count: usize,
+    },
+    /// evenly spaced around the arena, on the walls of the room
+    Walls {
+        room: Vec<(f64, f64)>,
+        arena: Arena,
+        count: usize,
+    },
     Free,
 }
 
@@ -25,6 +46,12 @@ impl SetupType {
                 get_from_stdin("  Camera index: ")?,
                 get_camera_distance_in_square(*side_length, fov),
             ),
+            SetupType::Around { arena, count } => {
+                calc_position_around(arena, camera_index(*count)?, *count, fov)?
+            }
+            SetupType::Walls { room, arena, count } => {
+                calc_position_on_walls(room, arena, camera_index(*count)?, *count, fov)?
+            }
             SetupType::Free => Position::new(
                 get_from_stdin("  x: ")?,
                 get_from_stdin("  y: ")?,
@@ -34,20 +61,74 @@ impl SetupType {
     }
     fn get() -> Result<Self> {
         match choice(
-            [("Square", true), ("Free", true)].into_iter(),
+            [
+                ("Square", true),
+                ("Around the arena", true),
+                ("On the walls", true),
+                ("Free", true),
+            ]
+            .into_iter(),
             Some("Select setup type: "),
-            Some(1),
+            Some(3),
         )? {
             0 => Ok(SetupType::Square {
                 side_length: get_from_stdin("Enter side length: ")?,
             }),
 
-            1 => Ok(SetupType::Free),
+            1 => Ok(SetupType::Around {
+                arena: get_arena()?,
+                count: get_from_stdin("Enter camera count: ")?,
+            }),
+
+            2 => Ok(SetupType::Walls {
+                room: get_polygon("Enter the number of corners of the room: ")?,
+                arena: get_arena()?,
+                count: get_from_stdin("Enter camera count: ")?,
+            }),
+
+            3 => Ok(SetupType::Free),
             _ => Err(anyhow!("Invalid index")),
         }
     }
 }
 
+/// the arena is centered on the origin
+fn get_arena() -> Result<Arena> {
+    match choice(
+        [("Circle", true), ("Rectangle", true)].into_iter(),
+        Some("Select arena shape: "),
+        Some(0),
+    )? {
+        0 => Ok(Arena::Circle {
+            radius: get_from_stdin("  Radius: ")?,
+        }),
+        1 => Ok(Arena::Rectangle {
+            width: get_from_stdin("  Width (along x): ")?,
+            height: get_from_stdin("  Height (along y): ")?,
+        }),
+        _ => Err(anyhow!("Invalid index")),
+    }
+}
+
+/// the corners of a polygon in order
+fn get_polygon(prompt: &str) -> Result<Vec<(f64, f64)>> {
+    let corners: usize = get_from_stdin(prompt)?;
+    (0..corners)
+        .map(|i| -> Result<_> {
+            println!("  Corner {i}");
+            Ok((get_from_stdin("    x: ")?, get_from_stdin("    y: ")?))
+        })
+        .collect()
+}
+
+fn camera_index(count: usize) -> Result<usize> {
+    let index = get_from_stdin("  Camera index: ")?;
+    if index >= count {
+        return Err(anyhow!("There are only {count} cameras"));
+    }
+    Ok(index)
+}
+
 macro_rules! get_hosts {
     ($organizer:ident, $pat:pat) => {{
         let options: Vec<(&Host, bool)> = $organizer
@@ -92,27 +173,91 @@ fn main() -> Result<()> {
         #[derive(Parser)]
         struct Args {
             /// The arcuco ids on the cube (counterclockwise)
-            #[arg(short, long, required = true, num_args = 4)]
-            cube: Vec<u8>,
+            #[arg(short, long, required_unless_present = "layout", num_args = 4)]
+            cube: Vec<u16>,
+
+            /// Deployment layout file to export to and apply from,
+            /// the cube is taken from it if not given
+            #[arg(short, long)]
+            layout: Option<String>,
+
+            /// The marker dictionary of the cube and the calibration board
+            #[arg(short, long, default_value_t = MarkerDictionary::default())]
+            dictionary: MarkerDictionary,
+
+            /// Side length of a printed calibration board square (m)
+            #[arg(long, default_value_t = 0.04)]
+            square_length: f32,
+
+            /// Side length of a printed calibration board marker (m)
+            #[arg(long, default_value_t = 0.02)]
+            marker_length: f32,
+
+            /// Keep calibration images and stop calibrating by their scores
+            /// instead of asking
+            #[arg(long)]
+            auto_calibration: bool,
         }
 
         Args::parse()
     };
-    let setup = SetupType::get()?;
+    let cube = if args.cube.is_empty() {
+        let path = args.layout.as_deref().unwrap_or_default();
+        Layout::load(path)
+            .map_err(|e| anyhow!("Couldn't load layout `{path}` because: {e}"))?
+            .cube
+    } else {
+        args.cube
+            .try_into()
+            .map_err(|_| anyhow!("The cube needs 4 ids"))?
+    };
+    let layout_path = args.layout.unwrap_or_else(|| "layout.json".to_string());
+    let mut setup = None;
 
     let mut buff = [0; 4096];
-    let mut organizer = Organizer::start(&mut buff, args.cube.try_into().unwrap())?;
+    let board = BoardConfig {
+        dictionary: args.dictionary,
+        square_length: args.square_length,
+        marker_length: args.marker_length,
+    };
+    let auto_calibration = args.auto_calibration.then_some(board);
+    let mut organizer = Organizer::start(&mut buff, cube, board)?;
 
     loop {
         organizer.scan()?;
-        handle_commands(&mut organizer, CliInterface { setup })?;
+        handle_commands(&mut organizer, &mut setup, &layout_path, auto_calibration)?;
     }
 }
 
 struct CliInterface {
     setup: SetupType,
+    /// the board to score calibration images with, `None` to ask
+    auto_calibration: Option<BoardConfig>,
+    gate: RefCell<Option<QualityGate>>,
 }
 impl CliInterface {
+    fn new(setup: SetupType, auto_calibration: Option<BoardConfig>) -> Self {
+        Self {
+            setup,
+            auto_calibration,
+            gate: RefCell::default(),
+        }
+    }
+
+    /// fails once the gate looked at too many images,
+    /// checked for every image since `more` isn't asked until one is kept
+    fn check_gave_up(gate: &QualityGate) -> Result<()> {
+        if gate.gave_up() {
+            return Err(anyhow!(
+                "Gave up after {} images, kept {} covering {:.0}% of the image",
+                gate.tried(),
+                gate.kept(),
+                gate.coverage() * 100.
+            ));
+        }
+        Ok(())
+    }
+
     fn more_inner(&self) -> Result<bool> {
         let more = yes_no_choice("  Continue?", false);
         if !more {
@@ -143,10 +288,16 @@ impl CalibrationInterface for CliInterface {
     type Parent = Self;
 
     fn get_board_size(&self) -> Result<(u8, u8), <Self::Parent as OrganizerInterface>::Error> {
-        Ok((
+        let (width, height) = (
             get_from_stdin("  Charuco board width: ")?,
             get_from_stdin("  Charuco board height: ")?,
-        ))
+        );
+
+        if let Some(board) = &self.auto_calibration {
+            let board = cv::generate_board(width, height, board)?;
+            *self.gate.borrow_mut() = Some(QualityGate::new(board, QualityThresholds::default()));
+        }
+        Ok((width, height))
     }
 
     fn keep_image(
@@ -154,6 +305,16 @@ impl CalibrationInterface for CliInterface {
         img: &opencv::prelude::Mat,
         board: &camloc_common::cv::FoundBoard,
     ) -> Result<bool, <Self::Parent as OrganizerInterface>::Error> {
+        if let Some(gate) = self.gate.borrow_mut().as_mut() {
+            Self::check_gave_up(gate)?;
+            let (quality, verdict) = gate.consider(img, board)?;
+            match verdict {
+                Ok(()) => println!("  Kept image: {quality}"),
+                Err(e) => println!("  Skipped image ({e}): {quality}"),
+            }
+            return Ok(verdict.is_ok());
+        }
+
         let mut img = img.clone();
         cv::draw_board(&mut img, board)?;
         display_image(&img, "recieved", true)?;
@@ -165,13 +326,26 @@ impl CalibrationInterface for CliInterface {
         &self,
         img: &opencv::prelude::Mat,
     ) -> Result<(), <Self::Parent as OrganizerInterface>::Error> {
+        if let Some(gate) = self.gate.borrow_mut().as_mut() {
+            Self::check_gave_up(gate)?;
+            gate.missed();
+            println!("  Board not found");
+            return Ok(());
+        }
+
         display_image(img, "recieved", true)?;
         print!("  Board not found\n  ");
         Ok(())
     }
 
     fn more(&self) -> Result<bool, <Self::Parent as OrganizerInterface>::Error> {
-        self.more_inner()
+        let gate = self.gate.borrow();
+        let Some(gate) = gate.as_ref() else {
+            return self.more_inner();
+        };
+
+        Self::check_gave_up(gate)?;
+        Ok(!gate.is_sufficient())
     }
 
     fn select_camera_position(
@@ -205,9 +379,52 @@ impl ImageStreamInterface for CliInterface {
     }
 }
 
+struct CliLocateInterface;
+
+impl LocateInterface for CliLocateInterface {
+    type Error = anyhow::Error;
+
+    fn show(
+        &self,
+        img: &opencv::prelude::Mat,
+        board: Option<&FoundBoard>,
+        camera: Option<Pose3>,
+    ) -> Result<(), Self::Error> {
+        let mut img = img.clone();
+        if let Some(board) = board {
+            cv::draw_board(&mut img, board)?;
+        }
+        display_image(&img, "recieved", true)?;
+
+        match camera {
+            Some(camera) => println!("  Camera at {camera}"),
+            None if board.is_some() => println!("  Too little of the board is visible"),
+            None => println!("  Board not found"),
+        }
+        Ok(())
+    }
+
+    fn more(&self, located: usize) -> Result<bool, Self::Error> {
+        Ok(yes_no_choice(
+            &format!("  Located from {located} images, continue?"),
+            located == 0,
+        ))
+    }
+}
+
+/// asks for the layout file, defaults to the one given on the command line
+fn layout_file(default: &str) -> String {
+    get_from_stdin::<String>(&format!("  Layout file ({default}): "))
+        .ok()
+        .filter(|p| !p.is_empty())
+        .unwrap_or_else(|| default.to_string())
+}
+
 fn handle_commands<const BUFFER_SIZE: usize>(
     organizer: &mut Organizer<'_, BUFFER_SIZE>,
-    interface: CliInterface,
+    setup: &mut Option<SetupType>,
+    layout_path: &str,
+    auto_calibration: Option<BoardConfig>,
 ) -> Result<()> {
     let server = match organizer.get_server() {
         Ok(s) => s,
@@ -234,10 +451,16 @@ fn handle_commands<const BUFFER_SIZE: usize>(
         List,
         Scan,
         Update,
+        Locate,
+        Coverage,
+        Export,
+        Apply,
         Quit,
     }
     use OrganizerCommand::*;
-    const COMMANDS: [OrganizerCommand; 6] = [Start, Stop, List, Scan, Update, Quit];
+    const COMMANDS: [OrganizerCommand; 10] = [
+        Start, Stop, List, Scan, Update, Locate, Coverage, Export, Apply, Quit,
+    ];
     impl std::fmt::Display for OrganizerCommand {
         fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
             write!(f, "{self:?}")
@@ -258,7 +481,11 @@ fn handle_commands<const BUFFER_SIZE: usize>(
     match cmd {
         Start => {
             let h = choose_host!(organizer, (_, HostState::Idle));
-            if let Err(e) = organizer.start_host(h, interface) {
+            let setup = match setup {
+                Some(s) => s.clone(),
+                None => setup.insert(SetupType::get()?).clone(),
+            };
+            if let Err(e) = organizer.start_host(h, CliInterface::new(setup, auto_calibration)) {
                 println!("Couldn't start client because: {e}");
             }
         }
@@ -290,13 +517,129 @@ fn handle_commands<const BUFFER_SIZE: usize>(
                 None
             };
 
-            organizer.update_info(h, position, fov)?;
+            if yes_no_choice("  Is the camera raised or tilted?", false) {
+                let mount = Pose3::new(
+                    [position.x, position.y, get_from_stdin("  height: ")?],
+                    Quaternion::from_yaw_pitch_roll(
+                        position.rotation,
+                        get_from_stdin::<f64>("  pitch (degrees, down is positive): ")?
+                            .to_radians(),
+                        get_from_stdin::<f64>("  roll (degrees): ")?.to_radians(),
+                    ),
+                );
+                organizer.update_mount(h, mount, fov)?;
+            } else {
+                organizer.update_info(h, position, fov)?;
+            }
+        }
+        Locate => {
+            let h = choose_host!(
+                organizer,
+                (HostType::Client { calibrated: true }, HostState::Idle)
+            );
+
+            println!("  Reference board (lying face up)");
+            let reference = ReferenceBoard::on_ground(
+                get_from_stdin("  Charuco board width: ")?,
+                get_from_stdin("  Charuco board height: ")?,
+                Position::new(
+                    get_from_stdin("  x of the top left corner: ")?,
+                    get_from_stdin("  y of the top left corner: ")?,
+                    get_from_stdin::<f64>("  direction of the top edge (degrees): ")?.to_radians(),
+                ),
+            );
+
+            match organizer.locate_host(h, &reference, CliLocateInterface) {
+                Ok(camera) => println!("Located client at {}", camera.pose()),
+                Err(e) => println!("Couldn't locate client because: {e}"),
+            }
+        }
+        Coverage => {
+            let layout = if yes_no_choice("  Use a layout file (or the placed cameras)?", false) {
+                let path = layout_file(layout_path);
+                match Layout::load(&path) {
+                    Ok(l) => l,
+                    Err(e) => {
+                        println!("Couldn't load layout because: {e}");
+                        return Ok(());
+                    }
+                }
+            } else {
+                organizer.layout()
+            };
+            let cameras: Vec<_> = layout.clients.iter().map(|c| c.camera).collect();
+
+            let arena = get_polygon("  Enter the number of corners of the arena: ")?;
+            let cell_size = get_from_stdin("  Cell size (0.05): ").unwrap_or(0.05);
+            let min_angle_diff = get_from_stdin("  Min camera angle difference (15 degrees): ")
+                .unwrap_or(15f64)
+                .to_radians();
+
+            let map = match CoverageMap::new(&cameras, &arena, min_angle_diff, cell_size) {
+                Ok(m) => m,
+                Err(e) => {
+                    println!("Couldn't make coverage map because: {e}");
+                    return Ok(());
+                }
+            };
+
+            let path: String = get_from_stdin("  Output file (.png or .csv): ")?;
+            let saved = if path.ends_with(".csv") {
+                std::fs::File::create(&path)
+                    .and_then(|f| map.write_csv(std::io::BufWriter::new(f)))
+                    .map_err(anyhow::Error::from)
+            } else {
+                let max_gdop = get_from_stdin("  Error shown as red (10): ").unwrap_or(10.);
+                cv::draw_coverage(&map, max_gdop, 8)
+                    .and_then(|img| {
+                        opencv::imgcodecs::imwrite(&path, &img, &opencv::core::Vector::new())
+                    })
+                    .map_err(anyhow::Error::from)
+                    .map(|_| ())
+            };
+            match saved {
+                Ok(()) => println!("Saved coverage map to `{path}`"),
+                Err(e) => println!("Couldn't save coverage map because: {e}"),
+            }
+        }
+        Export => {
+            let path = layout_file(layout_path);
+            match organizer.layout().save(&path) {
+                Ok(()) => println!("Saved layout to `{path}`"),
+                Err(e) => println!("Couldn't save layout because: {e}"),
+            }
+        }
+        Apply => {
+            let path = layout_file(layout_path);
+            let layout = match Layout::load(&path) {
+                Ok(l) => l,
+                Err(e) => {
+                    println!("Couldn't load layout because: {e}");
+                    return Ok(());
+                }
+            };
+
+            match organizer.apply_layout(&layout) {
+                Ok(applied) if applied.is_empty() => println!("No clients of the layout found"),
+                Ok(applied) => {
+                    for (h, res) in applied {
+                        match res {
+                            Ok(()) => println!("{h}: placed"),
+                            Err(e) => println!("{h}: couldn't place because: {e}"),
+                        }
+                    }
+                }
+                Err(e) => println!("Couldn't apply layout because: {e}"),
+            }
         }
         Quit => {
             println!("Quitting...");
             std::process::exit(0)
         }
     }
+    for a in organizer.take_dropped_connections() {
+        println!("Dropped connection from {a}, it wasn't being started");
+    }
     println!();
 
     Ok(())
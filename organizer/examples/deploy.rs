@@ -0,0 +1,82 @@
+//! Brings up a deployment without asking anything: waits for the server and the clients
+//! of the layout, starts the server, calibrates the clients that aren't calibrated yet
+//! (someone has to move the calibration board in front of them) and places every client.
+//!
+//! `cargo run --example deploy -- [config file, deploy.json by default]`,
+//! see `ScriptConfig` for the config, missing fields are the defaults
+
+use anyhow::{anyhow, Result};
+use camloc_common::{hosts::HostState, Layout};
+use camloc_organizer::{
+    scripted::{ScriptConfig, ScriptedInterface},
+    Organizer,
+};
+use std::time::Instant;
+
+fn main() -> Result<()> {
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "deploy.json".to_string());
+    let config = ScriptConfig::load(&path)
+        .map_err(|e| anyhow!("Couldn't load config `{path}` because: {e}"))?;
+    let layout = Layout::load(&config.layout)
+        .map_err(|e| anyhow!("Couldn't load layout `{}` because: {e}", config.layout))?;
+
+    let mut buff = [0; 4096];
+    let mut organizer = Organizer::start(&mut buff, layout.cube, config.board()?)?;
+
+    println!(
+        "Looking for the server and {} clients",
+        layout.clients.len()
+    );
+    let till = Instant::now() + config.discovery_timeout();
+    loop {
+        organizer.scan()?;
+
+        let found = organizer
+            .hosts()
+            .iter()
+            // they were seen earlier but not answering now, they wouldn't be placed
+            .filter(|h| h.info.host_state != HostState::Unreachable)
+            .filter(|h| {
+                layout
+                    .client(h.address().into(), organizer.identity(h))
+                    .is_some()
+            })
+            .count();
+        let server = organizer.get_server().is_ok();
+
+        if server && found == layout.clients.len() {
+            break;
+        }
+        if Instant::now() >= till {
+            if !server {
+                return Err(anyhow!("No server found"));
+            }
+            println!("Only found {found} of the {} clients", layout.clients.len());
+            break;
+        }
+    }
+
+    let applied = organizer.apply_layout_with(&layout, |c| {
+        ScriptedInterface::new(&config, c.camera.position)
+    })?;
+
+    // the ones that weren't found
+    let mut failed = layout.clients.len().saturating_sub(applied.len());
+    for (h, res) in applied {
+        match res {
+            Ok(()) => println!("{h}: placed"),
+            Err(e) => {
+                println!("{h}: couldn't place because: {e}");
+                failed += 1;
+            }
+        }
+    }
+
+    if failed > 0 {
+        return Err(anyhow!("{failed} clients couldn't be placed"));
+    }
+    println!("Deployed");
+    Ok(())
+}
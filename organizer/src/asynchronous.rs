@@ -0,0 +1,700 @@
+//! An organizer for async programs, keeps looking for hosts in the background
+
+use crate::{
+    config_bytes, find_server, GetImageError, GetServerError, Host, ImgLoopState, InfoUpdateError,
+    StartError, StartHostError, StartPhase, StartServerError, StartTimeouts, StopError,
+    MAX_IMAGE_SIZE,
+};
+use async_trait::async_trait;
+use camloc_common::{
+    cv::{self, BoardConfig, FoundBoard},
+    hosts::{
+        constants::{MAIN_PORT, ORGANIZER_STARTER_PORT},
+        Command, HostInfo, HostState, HostType,
+    },
+    Pose3, Position,
+};
+use opencv::{imgcodecs, prelude::*};
+use std::{
+    collections::HashMap,
+    mem::size_of,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    spawn,
+    sync::{broadcast, oneshot},
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+
+#[async_trait]
+pub trait AsyncCalibrationInterface: Send + Sync {
+    type Parent: AsyncOrganizerInterface;
+
+    async fn get_board_size(
+        &self,
+    ) -> Result<(u8, u8), <Self::Parent as AsyncOrganizerInterface>::Error>;
+
+    async fn keep_image(
+        &self,
+        img: &Mat,
+        board: &FoundBoard,
+    ) -> Result<bool, <Self::Parent as AsyncOrganizerInterface>::Error>;
+
+    async fn board_not_found(
+        &self,
+        img: &Mat,
+    ) -> Result<(), <Self::Parent as AsyncOrganizerInterface>::Error>;
+
+    async fn more(&self) -> Result<bool, <Self::Parent as AsyncOrganizerInterface>::Error>;
+
+    async fn select_camera_position(
+        self,
+        fov: f64,
+    ) -> Result<Position, <Self::Parent as AsyncOrganizerInterface>::Error>;
+}
+
+#[async_trait]
+pub trait AsyncImageStreamInterface: Send + Sync {
+    type Parent: AsyncOrganizerInterface;
+
+    async fn show(&self, img: &Mat)
+        -> Result<(), <Self::Parent as AsyncOrganizerInterface>::Error>;
+
+    async fn more(&self) -> Result<bool, <Self::Parent as AsyncOrganizerInterface>::Error>;
+
+    async fn select_camera_position(
+        self,
+        fov: f64,
+    ) -> Result<Position, <Self::Parent as AsyncOrganizerInterface>::Error>;
+}
+
+#[async_trait]
+pub trait AsyncOrganizerInterface: Send + Sync + Sized {
+    type CalibrationInterface: AsyncCalibrationInterface<Parent = Self>;
+    type ImageStreamInterface: AsyncImageStreamInterface<Parent = Self>;
+    type Error: Send;
+
+    async fn start_image_stream(self) -> Result<Self::ImageStreamInterface, Self::Error>;
+    async fn start_calibration(self) -> Result<Self::CalibrationInterface, Self::Error>;
+}
+
+/// Changes of the hosts found by the background discovery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostEvent {
+    Found(Host),
+    /// the host's type or state changed
+    Changed(Host),
+    /// the host stopped answering pings, it's kept as unreachable
+    Lost(Host),
+}
+
+struct Seen {
+    host: Host,
+    at: Instant,
+}
+
+struct Shared {
+    sock: UdpSocket,
+    hosts: Mutex<Vec<Seen>>,
+    event_tx: broadcast::Sender<HostEvent>,
+    /// starts waiting for a client to connect by its ip,
+    /// the cameras of a client connect from the same ip so only one of them waits at a time
+    connections: Mutex<HashMap<Ipv4Addr, oneshot::Sender<TcpStream>>>,
+    /// starts waiting for a configless client's fov by its address
+    configless: Mutex<HashMap<SocketAddr, oneshot::Sender<f64>>>,
+    cancel_token: CancellationToken,
+}
+
+impl Shared {
+    fn send_event(&self, e: HostEvent) {
+        let _ = self.event_tx.send(e);
+    }
+}
+
+pub struct Builder {
+    cube: [u16; 4],
+    board: BoardConfig,
+    timeouts: StartTimeouts,
+    scan_interval: Duration,
+    cancel_token: CancellationToken,
+}
+
+impl Builder {
+    pub fn new(cube: [u16; 4]) -> Self {
+        Self {
+            cube,
+            board: BoardConfig::default(),
+            timeouts: StartTimeouts::default(),
+            scan_interval: Duration::from_secs(2),
+            cancel_token: CancellationToken::new(),
+        }
+    }
+    pub fn with_board(mut self, v: BoardConfig) -> Self {
+        self.board = v;
+        self
+    }
+    pub fn with_timeouts(mut self, v: StartTimeouts) -> Self {
+        self.timeouts = v;
+        self
+    }
+    /// How often hosts are pinged, hosts that miss two pings are lost
+    pub fn with_scan_interval(mut self, v: Duration) -> Self {
+        self.scan_interval = v;
+        self
+    }
+    pub fn with_cancellation_token(mut self, v: CancellationToken) -> Self {
+        self.cancel_token = v;
+        self
+    }
+
+    pub async fn start(self) -> std::io::Result<AsyncOrganizer> {
+        let sock = UdpSocket::bind(("0.0.0.0", 0)).await?;
+        sock.set_broadcast(true)?;
+        let listener = TcpListener::bind(("0.0.0.0", ORGANIZER_STARTER_PORT)).await?;
+
+        let (event_tx, event_rx) = broadcast::channel(1024);
+        drop(event_rx);
+
+        let shared = Arc::new(Shared {
+            sock,
+            hosts: Mutex::new(vec![]),
+            event_tx,
+            connections: Mutex::new(HashMap::new()),
+            configless: Mutex::new(HashMap::new()),
+            // dropping the organizer stops its tasks, but not whatever else uses the token
+            cancel_token: self.cancel_token.child_token(),
+        });
+
+        let tasks = vec![
+            spawn(discover(shared.clone(), self.scan_interval)),
+            spawn(accept(shared.clone(), listener)),
+        ];
+
+        Ok(AsyncOrganizer {
+            shared,
+            tasks,
+            cube: self.cube,
+            board: self.board,
+            timeouts: self.timeouts,
+        })
+    }
+}
+
+pub struct AsyncOrganizer {
+    shared: Arc<Shared>,
+    tasks: Vec<JoinHandle<std::io::Result<()>>>,
+    cube: [u16; 4],
+    board: BoardConfig,
+    timeouts: StartTimeouts,
+}
+
+impl AsyncOrganizer {
+    pub fn hosts(&self) -> Vec<Host> {
+        let hosts = self.shared.hosts.lock().unwrap();
+        hosts.iter().map(|s| s.host).collect()
+    }
+
+    pub fn get_event_channel(&self) -> broadcast::Receiver<HostEvent> {
+        self.shared.event_tx.subscribe()
+    }
+
+    pub fn get_server(&self) -> Result<Host, GetServerError> {
+        find_server(&self.hosts()).copied()
+    }
+
+    pub async fn start_server(&self) -> Result<(), StartServerError> {
+        self.shared
+            .sock
+            .send_to(
+                &Into::<Vec<u8>>::into(Command::StartServer { cube: self.cube }),
+                (self.get_server()?.ip(), MAIN_PORT),
+            )
+            .await?;
+        Ok(())
+    }
+
+    pub async fn update_info(
+        &self,
+        host: Host,
+        position: Position,
+        fov: Option<f64>,
+    ) -> Result<(), InfoUpdateError> {
+        self.send_to_server(Command::InfoUpdate {
+            client_ip: &host.address().to_string(),
+            position,
+            fov,
+            mount: None,
+        })
+        .await
+    }
+
+    /// Like `update_info`, but with the full 3D pose of the camera
+    pub async fn update_mount(
+        &self,
+        host: Host,
+        mount: Pose3,
+        fov: Option<f64>,
+    ) -> Result<(), InfoUpdateError> {
+        self.send_to_server(Command::InfoUpdate {
+            client_ip: &host.address().to_string(),
+            position: mount.into(),
+            fov,
+            mount: Some(mount),
+        })
+        .await
+    }
+
+    async fn send_to_server(&self, command: Command<'_>) -> Result<(), InfoUpdateError> {
+        let server = self.get_server()?;
+        self.shared
+            .sock
+            .send_to(&Into::<Vec<u8>>::into(command), (server.ip(), MAIN_PORT))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn stop_host(&self, host: Host) -> Result<(), StopError> {
+        if host.info.host_state != HostState::Running {
+            return Err(StopError::NotRunning(host));
+        }
+
+        self.shared
+            .sock
+            .send_to(&[Command::STOP], host.address())
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn start_host<I: AsyncOrganizerInterface>(
+        &self,
+        host: Host,
+        interface: I,
+    ) -> Result<(), StartHostError<I::Error>> {
+        self.start_host_cancellable(host, interface, &CancellationToken::new())
+            .await
+    }
+
+    /// Like `start_host`, but gives up once `cancel` is cancelled.
+    /// Several hosts can be started at the same time.
+    pub async fn start_host_cancellable<I: AsyncOrganizerInterface>(
+        &self,
+        host: Host,
+        interface: I,
+        cancel: &CancellationToken,
+    ) -> Result<(), StartHostError<I::Error>> {
+        use StartPhase::*;
+
+        let calibrated = match host.info.host_type {
+            HostType::Server => {
+                return self
+                    .start_server()
+                    .await
+                    .map_err(|e| StartError::from(e).during(Connect))
+            }
+            HostType::ConfiglessClient => {
+                return self.start_configless(host, interface, cancel).await
+            }
+            HostType::Client { calibrated } => calibrated,
+        };
+
+        let mut s = cancellable(cancel, self.connect(host))
+            .await
+            .map_err(|e| e.during(Connect))?;
+
+        let images = cancellable(cancel, self.collect_images(&mut s, calibrated, interface))
+            .await
+            .map_err(|e| e.during(Images))?;
+
+        let (pos, calib) = match images {
+            ImgLoopState::Calibrating {
+                interface,
+                board,
+                images,
+            } => {
+                let calibration = tokio::task::spawn_blocking(move || {
+                    let size = images[0].size()?;
+                    cv::calibrate(&board, &images, size)
+                });
+                let calib = cancellable(
+                    cancel,
+                    with_timeout(self.timeouts.calibration, async {
+                        Ok::<_, StartError<I::Error>>(
+                            calibration
+                                .await
+                                .map_err(|_| StartError::CalibrationCrashed)??,
+                        )
+                    }),
+                )
+                .await
+                .map_err(|e| e.during(Calibration))?;
+
+                let pos = interface
+                    .select_camera_position(calib.horizontal_fov)
+                    .await
+                    .map_err(|e| StartError::Interface(e).during(Config))?;
+
+                (pos, Some(calib))
+            }
+
+            ImgLoopState::Showing { interface } => {
+                let mut fov = [0; size_of::<f64>()];
+                cancellable(
+                    cancel,
+                    with_timeout(self.timeouts.config, async {
+                        s.read_exact(&mut fov).await?;
+                        Ok::<_, StartError<I::Error>>(())
+                    }),
+                )
+                .await
+                .map_err(|e| e.during(Config))?;
+                let fov = f64::from_be_bytes(fov);
+
+                let pos = interface
+                    .select_camera_position(fov)
+                    .await
+                    .map_err(|e| StartError::Interface(e).during(Config))?;
+
+                (pos, None)
+            }
+        };
+
+        let config = self
+            .get_server()
+            .map(|server| config_bytes(pos, server.ip(), calib.as_ref(), self.cube, &self.board))
+            .map_err(|e| StartError::from(e).during(Config))?;
+
+        cancellable(
+            cancel,
+            with_timeout(self.timeouts.config, async {
+                s.write_all(&config).await?;
+                Ok::<_, StartError<I::Error>>(())
+            }),
+        )
+        .await
+        .map_err(|e| e.during(Config))
+    }
+
+    /// sends `START` and waits for the client to connect
+    async fn connect<E>(&self, host: Host) -> Result<TcpStream, StartError<E>> {
+        with_timeout(self.timeouts.connect, async {
+            let rx = loop {
+                // closed senders belong to starts that gave up
+                let waiting = {
+                    let mut connections = self.shared.connections.lock().unwrap();
+                    match connections.get(&host.ip) {
+                        Some(tx) if !tx.is_closed() => None,
+                        _ => {
+                            let (tx, rx) = oneshot::channel();
+                            connections.insert(host.ip, tx);
+                            Some(rx)
+                        }
+                    }
+                };
+
+                match waiting {
+                    Some(rx) => break rx,
+                    None => tokio::time::sleep(POLL_INTERVAL).await,
+                }
+            };
+
+            self.shared
+                .sock
+                .send_to(&[Command::START], host.address())
+                .await?;
+
+            rx.await.map_err(|_| StartError::Cancelled)
+        })
+        .await
+    }
+
+    async fn collect_images<I: AsyncOrganizerInterface>(
+        &self,
+        s: &mut TcpStream,
+        calibrated: bool,
+        interface: I,
+    ) -> Result<ImgLoopState<I::CalibrationInterface, I::ImageStreamInterface>, StartError<I::Error>>
+    {
+        let mut state = if calibrated {
+            ImgLoopState::Showing {
+                interface: interface
+                    .start_image_stream()
+                    .await
+                    .map_err(StartError::Interface)?,
+            }
+        } else {
+            let interface = interface
+                .start_calibration()
+                .await
+                .map_err(StartError::Interface)?;
+            let (width, height) = interface
+                .get_board_size()
+                .await
+                .map_err(StartError::Interface)?;
+            ImgLoopState::Calibrating {
+                board: cv::generate_board(width, height, &self.board)?,
+                images: vec![],
+                interface,
+            }
+        };
+
+        loop {
+            let img = with_timeout(self.timeouts.image, async {
+                s.write_all(&[Command::REQUEST_IMAGE]).await?;
+                Ok::<_, StartError<I::Error>>(get_image(s).await?)
+            })
+            .await?;
+
+            match &mut state {
+                ImgLoopState::Calibrating {
+                    board,
+                    images,
+                    interface,
+                } => {
+                    let detection = cv::find_board(&img, board, false)?;
+
+                    if let Some(fb) = detection {
+                        if interface
+                            .keep_image(&img, &fb)
+                            .await
+                            .map_err(StartError::Interface)?
+                        {
+                            images.push(img);
+                        }
+                    } else {
+                        interface
+                            .board_not_found(&img)
+                            .await
+                            .map_err(StartError::Interface)?;
+                    }
+                    if !images.is_empty()
+                        && !interface.more().await.map_err(StartError::Interface)?
+                    {
+                        break;
+                    }
+                }
+
+                ImgLoopState::Showing { interface } => {
+                    interface.show(&img).await.map_err(StartError::Interface)?;
+                    if !interface.more().await.map_err(StartError::Interface)? {
+                        break;
+                    }
+                }
+            }
+        }
+        s.write_all(&[Command::IMAGES_DONE]).await?;
+
+        Ok(state)
+    }
+
+    /// Configless clients only get the server's ip,
+    /// they answer with the fov they connected to the server with
+    async fn start_configless<I: AsyncOrganizerInterface>(
+        &self,
+        host: Host,
+        interface: I,
+        cancel: &CancellationToken,
+    ) -> Result<(), StartHostError<I::Error>> {
+        let server = self
+            .get_server()
+            .map_err(|e| StartError::from(e).during(StartPhase::Connect))?
+            .ip();
+
+        let fov = cancellable(
+            cancel,
+            with_timeout(self.timeouts.connect, async {
+                let (tx, rx) = oneshot::channel();
+                self.shared
+                    .configless
+                    .lock()
+                    .unwrap()
+                    .insert(host.address().into(), tx);
+
+                self.shared
+                    .sock
+                    .send_to(
+                        &Into::<Vec<u8>>::into(Command::StartConfigless {
+                            ip: &server.to_string(),
+                        }),
+                        host.address(),
+                    )
+                    .await?;
+
+                rx.await.map_err(|_| StartError::Cancelled)
+            }),
+        )
+        .await
+        .map_err(|e| e.during(StartPhase::Connect))?;
+
+        let position = match interface.start_image_stream().await {
+            Ok(i) => i.select_camera_position(fov).await,
+            Err(e) => Err(e),
+        }
+        .map_err(|e| StartError::Interface(e).during(StartPhase::Config))?;
+
+        // the client connected with a placeholder position
+        self.update_info(host, position, None).await.map_err(|e| {
+            match e {
+                InfoUpdateError::Io(e) => StartError::Io(e),
+                InfoUpdateError::GetServer(e) => StartError::GetServer(e),
+            }
+            .during(StartPhase::Config)
+        })
+    }
+
+    /// Stops the background tasks
+    pub async fn stop(mut self) -> std::io::Result<()> {
+        let tasks = std::mem::take(&mut self.tasks);
+        drop(self);
+
+        for t in tasks {
+            t.await.expect("organizer task panicked")?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AsyncOrganizer {
+    fn drop(&mut self) {
+        self.shared.cancel_token.cancel();
+    }
+}
+
+/// pings the hosts and keeps track of their answers
+async fn discover(shared: Arc<Shared>, scan_interval: Duration) -> std::io::Result<()> {
+    let mut ping = tokio::time::interval(scan_interval);
+    let mut buf = [0; 256];
+
+    loop {
+        tokio::select! {
+            _ = shared.cancel_token.cancelled() => return Ok(()),
+
+            _ = ping.tick() => {
+                // the network may be down for a moment, the next ping tries again
+                let _ = shared
+                    .sock
+                    .send_to(&[Command::PING], (IpAddr::V4(Ipv4Addr::BROADCAST), MAIN_PORT))
+                    .await;
+
+                // nothing could have missed two pings this early
+                let Some(lost_before) = Instant::now().checked_sub(2 * scan_interval) else {
+                    continue;
+                };
+                let mut hosts = shared.hosts.lock().unwrap();
+                for seen in hosts.iter_mut() {
+                    if seen.at < lost_before && seen.host.info.host_state != HostState::Unreachable {
+                        seen.host.info.host_state = HostState::Unreachable;
+                        shared.send_event(HostEvent::Lost(seen.host));
+                    }
+                }
+            }
+
+            // errors like an icmp port unreachable from an earlier send don't stop discovery
+            Ok((len, addr)) = shared.sock.recv_from(&mut buf) => {
+                handle_answer(&shared, &buf[..len], addr);
+            }
+        }
+    }
+}
+
+fn handle_answer(shared: &Shared, answer: &[u8], addr: SocketAddr) {
+    // the fov of a configless client that's being started
+    if let Ok(Command::Connect { fov, .. }) = answer.try_into() {
+        if let Some(tx) = shared.configless.lock().unwrap().remove(&addr) {
+            let _ = tx.send(fov);
+        }
+        return;
+    }
+
+    let (&[info], SocketAddr::V4(addr)) = (answer, addr) else {
+        return;
+    };
+    let Ok(info): Result<HostInfo, _> = info.try_into() else {
+        return;
+    };
+
+    let host = Host {
+        info,
+        ip: *addr.ip(),
+        port: addr.port(),
+    };
+
+    let mut hosts = shared.hosts.lock().unwrap();
+    let seen = hosts
+        .iter_mut()
+        .find(|s| s.host.ip == host.ip && s.host.port == host.port);
+
+    match seen {
+        Some(seen) => {
+            seen.at = Instant::now();
+            if seen.host != host {
+                seen.host = host;
+                shared.send_event(HostEvent::Changed(host));
+            }
+        }
+        None => {
+            hosts.push(Seen {
+                host,
+                at: Instant::now(),
+            });
+            shared.send_event(HostEvent::Found(host));
+        }
+    }
+}
+
+/// hands the clients' connections over to the starts waiting for them
+async fn accept(shared: Arc<Shared>, listener: TcpListener) -> std::io::Result<()> {
+    loop {
+        let (s, addr) = tokio::select! {
+            _ = shared.cancel_token.cancelled() => return Ok(()),
+            r = listener.accept() => r?,
+        };
+
+        // nobody is waiting for it, stray connections are dropped
+        if let IpAddr::V4(ip) = addr.ip() {
+            if let Some(tx) = shared.connections.lock().unwrap().remove(&ip) {
+                let _ = tx.send(s);
+            }
+        }
+    }
+}
+
+async fn cancellable<T, E>(
+    cancel: &CancellationToken,
+    f: impl std::future::Future<Output = Result<T, StartError<E>>>,
+) -> Result<T, StartError<E>> {
+    tokio::select! {
+        r = f => r,
+        _ = cancel.cancelled() => Err(StartError::Cancelled),
+    }
+}
+
+async fn with_timeout<T, E>(
+    duration: Duration,
+    f: impl std::future::Future<Output = Result<T, StartError<E>>>,
+) -> Result<T, StartError<E>> {
+    timeout(duration, f)
+        .await
+        .unwrap_or(Err(StartError::TimedOut))
+}
+
+async fn get_image(r: &mut (impl AsyncRead + Unpin)) -> Result<Mat, GetImageError> {
+    let len = r.read_u64().await?;
+    if len > MAX_IMAGE_SIZE {
+        return Err(GetImageError::TooLarge(len));
+    }
+    let len = len as usize;
+
+    let mut buffer = opencv::core::Vector::from_elem(0, len);
+    r.read_exact(&mut buffer.as_mut_slice()[..len]).await?;
+
+    Ok(imgcodecs::imdecode(&buffer, imgcodecs::IMREAD_COLOR)?)
+}
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
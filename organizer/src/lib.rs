@@ -1,18 +1,30 @@
 use camloc_common::{
-    cv::{self, FoundBoard},
+    cv::{self, BoardConfig, FoundBoard, FullCameraInfo, ReferenceBoard},
     hosts::constants::{MAIN_PORT, ORGANIZER_STARTER_PORT},
     hosts::{Command, HostInfo, HostState, HostType},
-    Position,
+    pose::Quaternion,
+    ClientIdentity, ClientLayout, Layout, PlacedCamera, Pose3, Position,
 };
 use opencv::{self, imgcodecs, objdetect::CharucoBoard, prelude::*};
 use std::{
+    collections::HashMap,
     io::{Read, Write},
     mem::size_of,
-    net::{IpAddr, Ipv4Addr, TcpListener, UdpSocket},
+    net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
     time::{Duration, Instant},
 };
 use thiserror::Error as ThisError;
 
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+pub mod quality;
+pub mod scripted;
+
 #[derive(ThisError, Debug)]
 pub enum GetServerError {
     #[error("No running server")]
@@ -70,6 +82,111 @@ pub enum StartError<I> {
 
     #[error("interface error: {0}")]
     Interface(I),
+
+    #[error("timed out")]
+    TimedOut,
+
+    #[error("cancelled")]
+    Cancelled,
+
+    #[error("calibration crashed")]
+    CalibrationCrashed,
+}
+
+impl<I> StartError<I> {
+    fn during(self, phase: StartPhase) -> StartHostError<I> {
+        StartHostError { phase, error: self }
+    }
+}
+
+/// The steps of starting a host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartPhase {
+    /// waiting for the client to connect (or a configless client to answer)
+    Connect,
+    /// getting images from the client
+    Images,
+    /// calibrating from the images
+    Calibration,
+    /// selecting the position and sending the config
+    Config,
+}
+
+impl std::fmt::Display for StartPhase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            StartPhase::Connect => "connecting",
+            StartPhase::Images => "getting images",
+            StartPhase::Calibration => "calibrating",
+            StartPhase::Config => "sending config",
+        })
+    }
+}
+
+/// A `StartError` and the step it happened in
+#[derive(ThisError, Debug)]
+#[error("{phase} failed: {error}")]
+pub struct StartHostError<I> {
+    pub phase: StartPhase,
+    pub error: StartError<I>,
+}
+
+/// How long starting a host may wait for the client in each step
+#[derive(Debug, Clone, Copy)]
+pub struct StartTimeouts {
+    /// for the client to connect (or a configless client to answer)
+    pub connect: Duration,
+    /// for each image
+    pub image: Duration,
+    /// for the client's fov and for sending the config
+    pub config: Duration,
+    /// for calibrating from the images,
+    /// the calibration can't be interrupted so it finishes in the background
+    pub calibration: Duration,
+}
+
+impl Default for StartTimeouts {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(5),
+            image: Duration::from_secs(10),
+            config: Duration::from_secs(5),
+            calibration: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Cancels starting hosts, from another thread
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(ThisError, Debug)]
+pub enum LocateError<I> {
+    #[error("only idle calibrated clients can be located ({0})")]
+    NotCalibrated(Host),
+
+    #[error("the reference board wasn't found")]
+    BoardNotFound,
+
+    #[error(transparent)]
+    Start(#[from] StartHostError<I>),
+
+    #[error(transparent)]
+    InfoUpdate(#[from] InfoUpdateError),
 }
 
 #[derive(ThisError, Debug)]
@@ -79,6 +196,9 @@ pub enum GetImageError {
 
     #[error("opencv error: {0}")]
     OpenCV(#[from] opencv::Error),
+
+    #[error("the image is too large ({0} bytes)")]
+    TooLarge(u64),
 }
 
 #[derive(ThisError, Debug)]
@@ -91,10 +211,16 @@ pub enum ScanError {
 pub struct Host {
     info: HostInfo,
     ip: Ipv4Addr,
+    /// clients with several cameras answer from a different port for each camera
+    port: u16,
 }
 impl std::fmt::Display for Host {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let ip = &self.ip;
+        let ip = if self.port == MAIN_PORT {
+            self.ip.to_string()
+        } else {
+            self.address().to_string()
+        };
         match &self.info.host_type {
             HostType::Client { calibrated } => {
                 write!(f, "CLIENT {ip}")?;
@@ -118,6 +244,14 @@ impl Host {
     pub fn ip(&self) -> Ipv4Addr {
         self.ip
     }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn address(&self) -> SocketAddrV4 {
+        SocketAddrV4::new(self.ip, self.port)
+    }
 }
 
 pub struct Organizer<'a, const BUFFER_SIZE: usize> {
@@ -125,7 +259,22 @@ pub struct Organizer<'a, const BUFFER_SIZE: usize> {
     server_sock: TcpListener,
     hosts: Vec<Host>,
     sock: UdpSocket,
-    cube: [u8; 4],
+    cube: [u16; 4],
+    board: BoardConfig,
+    timeouts: StartTimeouts,
+    /// ips of the clients being started
+    starting: Mutex<Vec<Ipv4Addr>>,
+    starting_changed: Condvar,
+    /// connections accepted while starting another client, and when
+    accepted: Mutex<Vec<(TcpStream, Instant)>>,
+    /// where connections nobody was waiting for came from
+    dropped: Mutex<Vec<SocketAddr>>,
+    /// held while waiting for a configless client's answer
+    configless: Mutex<()>,
+    /// where the started clients were placed
+    placements: Mutex<HashMap<SocketAddrV4, PlacedCamera>>,
+    /// the cameras the clients said they have at their addresses
+    identities: HashMap<SocketAddrV4, ClientIdentity>,
 }
 
 pub trait CalibrationInterface {
@@ -172,6 +321,105 @@ pub trait OrganizerInterface {
     fn start_calibration(self) -> Result<Self::CalibrationInterface, Self::Error>;
 }
 
+/// Shows the images of a client while it's being located
+pub trait LocateInterface {
+    type Error;
+
+    /// `camera` is where the camera was located from this image
+    fn show(
+        &self,
+        img: &Mat,
+        board: Option<&FoundBoard>,
+        camera: Option<Pose3>,
+    ) -> Result<(), Self::Error>;
+
+    /// `located` is the number of images the camera was located from so far
+    fn more(&self, located: usize) -> Result<bool, Self::Error>;
+}
+
+#[derive(ThisError, Debug)]
+#[error("the client has to be calibrated first")]
+pub struct NeedsCalibration;
+
+#[derive(ThisError, Debug)]
+pub enum ApplyLayoutError {
+    #[error(transparent)]
+    StartServer(#[from] StartServerError),
+
+    #[error(transparent)]
+    GetServer(#[from] GetServerError),
+
+    #[error("the layout is for the server at {expected}, but the server is at {found}")]
+    WrongServer { expected: IpAddr, found: Ipv4Addr },
+}
+
+#[derive(ThisError, Debug)]
+pub enum ApplyError<I = NeedsCalibration> {
+    #[error(transparent)]
+    Start(#[from] StartHostError<I>),
+
+    #[error(transparent)]
+    InfoUpdate(#[from] InfoUpdateError),
+}
+
+/// Starts calibrated clients at a known position without showing anything
+struct Placed(Position);
+
+impl OrganizerInterface for Placed {
+    type CalibrationInterface = Placed;
+    type ImageStreamInterface = Placed;
+    type Error = NeedsCalibration;
+
+    fn start_image_stream(self) -> Result<Self::ImageStreamInterface, Self::Error> {
+        Ok(self)
+    }
+
+    fn start_calibration(self) -> Result<Self::CalibrationInterface, Self::Error> {
+        Err(NeedsCalibration)
+    }
+}
+
+impl ImageStreamInterface for Placed {
+    type Parent = Placed;
+
+    fn show(&self, _img: &Mat) -> Result<(), NeedsCalibration> {
+        Ok(())
+    }
+
+    fn more(&self) -> Result<bool, NeedsCalibration> {
+        Ok(false)
+    }
+
+    fn select_camera_position(self, _fov: f64) -> Result<Position, NeedsCalibration> {
+        Ok(self.0)
+    }
+}
+
+/// never started, `start_calibration` fails
+impl CalibrationInterface for Placed {
+    type Parent = Placed;
+
+    fn get_board_size(&self) -> Result<(u8, u8), NeedsCalibration> {
+        Err(NeedsCalibration)
+    }
+
+    fn keep_image(&self, _img: &Mat, _board: &FoundBoard) -> Result<bool, NeedsCalibration> {
+        Err(NeedsCalibration)
+    }
+
+    fn board_not_found(&self, _img: &Mat) -> Result<(), NeedsCalibration> {
+        Err(NeedsCalibration)
+    }
+
+    fn more(&self) -> Result<bool, NeedsCalibration> {
+        Err(NeedsCalibration)
+    }
+
+    fn select_camera_position(self, _fov: f64) -> Result<Position, NeedsCalibration> {
+        Err(NeedsCalibration)
+    }
+}
+
 enum ImgLoopState<C, S> {
     Calibrating {
         board: CharucoBoard,
@@ -184,20 +432,41 @@ enum ImgLoopState<C, S> {
 }
 
 impl<'o, const BUFFER_SIZE: usize> Organizer<'o, BUFFER_SIZE> {
-    pub fn start(buffer: &'o mut [u8; BUFFER_SIZE], cube: [u8; 4]) -> std::io::Result<Self> {
+    pub fn start(
+        buffer: &'o mut [u8; BUFFER_SIZE],
+        cube: [u16; 4],
+        board: BoardConfig,
+    ) -> std::io::Result<Self> {
         let sock = UdpSocket::bind(("0.0.0.0", 0))?;
         sock.set_broadcast(true)?;
         sock.set_read_timeout(Some(TIMEOUT_DURATION))?;
 
+        let server_sock = TcpListener::bind(("0.0.0.0", ORGANIZER_STARTER_PORT))?;
+        server_sock.set_nonblocking(true)?;
+
         Ok(Self {
-            server_sock: TcpListener::bind(("0.0.0.0", ORGANIZER_STARTER_PORT))?,
+            server_sock,
             sock,
             hosts: vec![],
             buffer,
             cube,
+            board,
+            timeouts: StartTimeouts::default(),
+            starting: Mutex::new(vec![]),
+            starting_changed: Condvar::new(),
+            accepted: Mutex::new(vec![]),
+            dropped: Mutex::new(vec![]),
+            configless: Mutex::new(()),
+            placements: Mutex::new(HashMap::new()),
+            identities: HashMap::new(),
         })
     }
 
+    pub fn with_timeouts(mut self, timeouts: StartTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
     pub fn update_info(
         &mut self,
         host: Host,
@@ -206,42 +475,165 @@ impl<'o, const BUFFER_SIZE: usize> Organizer<'o, BUFFER_SIZE> {
     ) -> Result<(), InfoUpdateError> {
         self.sock.send_to(
             &Into::<Vec<u8>>::into(Command::InfoUpdate {
-                client_ip: &host.ip.to_string(),
+                client_ip: &host.address().to_string(),
                 position,
                 fov,
+                mount: None,
             }),
             (self.get_server()?.ip, MAIN_PORT),
         )?;
+
+        let placements = self.placements.get_mut().unwrap();
+        if let Some(fov) = fov.or(placements.get(&host.address()).map(|c| c.fov)) {
+            placements.insert(host.address(), PlacedCamera::new(position, fov));
+        }
         Ok(())
     }
 
-    pub fn hosts(&self) -> &[Host] {
-        &self.hosts
+    /// Like `update_info`, but with the full 3D pose of the camera
+    pub fn update_mount(
+        &mut self,
+        host: Host,
+        mount: Pose3,
+        fov: Option<f64>,
+    ) -> Result<(), InfoUpdateError> {
+        self.sock.send_to(
+            &Into::<Vec<u8>>::into(Command::InfoUpdate {
+                client_ip: &host.address().to_string(),
+                position: mount.into(),
+                fov,
+                mount: Some(mount),
+            }),
+            (self.get_server()?.ip, MAIN_PORT),
+        )?;
+
+        let placements = self.placements.get_mut().unwrap();
+        if let Some(fov) = fov.or(placements.get(&host.address()).map(|c| c.fov)) {
+            placements.insert(host.address(), PlacedCamera::mounted(mount, fov));
+        }
+        Ok(())
     }
 
-    pub fn get_server(&self) -> Result<&Host, GetServerError> {
-        let mut si = Err(GetServerError::NoServer);
+    fn place(&self, host: Host, camera: PlacedCamera) {
+        self.placements
+            .lock()
+            .unwrap()
+            .insert(host.address(), camera);
+    }
 
-        for h in &self.hosts {
-            if matches!(
-                h.info,
-                HostInfo {
-                    host_type: HostType::Server,
-                    host_state: HostState::Idle | HostState::Running
-                },
-            ) {
-                si = match si {
-                    Ok(_) => Err(GetServerError::Multiple(1)),
-                    Err(GetServerError::NoServer) => Ok(h),
-                    Err(GetServerError::Multiple(n)) => Err(GetServerError::Multiple(n + 1)),
-                };
+    /// The cube, the server and where the started clients were placed
+    pub fn layout(&self) -> Layout {
+        let placements = self.placements.lock().unwrap();
+        Layout {
+            cube: self.cube,
+            server: self.get_server().ok().map(|s| IpAddr::V4(s.ip)),
+            clients: placements
+                .iter()
+                .map(|(address, camera)| ClientLayout {
+                    address: (*address).into(),
+                    identity: self.identities.get(address).cloned(),
+                    camera: *camera,
+                })
+                .collect(),
+        }
+    }
+
+    /// Starts the server if it's idle and places every reachable client of the layout,
+    /// idle clients are started (they have to be calibrated already).
+    /// Clients are found by their cameras (see `Layout::client`).
+    pub fn apply_layout(
+        &mut self,
+        layout: &Layout,
+    ) -> Result<Vec<(Host, Result<(), ApplyError>)>, ApplyLayoutError> {
+        self.apply_layout_with(layout, |c| Placed(c.camera.position))
+    }
+
+    /// Like `apply_layout`, but idle clients are started with the interface made for them
+    /// (which should select the position of the layout)
+    pub fn apply_layout_with<I: OrganizerInterface>(
+        &mut self,
+        layout: &Layout,
+        mut interface: impl FnMut(&ClientLayout) -> I,
+    ) -> Result<Vec<(Host, Result<(), ApplyError<I::Error>>)>, ApplyLayoutError> {
+        let server = *self.get_server()?;
+        if let Some(expected) = layout.server.filter(|ip| *ip != IpAddr::V4(server.ip)) {
+            return Err(ApplyLayoutError::WrongServer {
+                expected,
+                found: server.ip,
+            });
+        }
+
+        self.cube = layout.cube;
+        if server.info.host_state == HostState::Idle {
+            self.start_server()?;
+        }
+
+        let hosts: Vec<_> = self
+            .hosts
+            .iter()
+            .filter(|h| h.info.host_state != HostState::Unreachable)
+            .filter_map(|h| {
+                Some((
+                    *h,
+                    layout.client(h.address().into(), self.identity(h))?.clone(),
+                ))
+            })
+            .collect();
+
+        Ok(hosts
+            .into_iter()
+            .map(|(host, client)| {
+                let interface = interface(&client);
+                (host, self.apply_placement(host, &client, interface))
+            })
+            .collect())
+    }
+
+    fn apply_placement<I: OrganizerInterface>(
+        &mut self,
+        host: Host,
+        client: &ClientLayout,
+        interface: I,
+    ) -> Result<(), ApplyError<I::Error>> {
+        let camera = client.camera;
+
+        if host.info.host_state == HostState::Idle {
+            self.start_host(host, interface)?;
+
+            // the position was sent with the config, the server keeps the rest
+            // until the client connects
+            if camera.mount.is_none() {
+                return Ok(());
             }
         }
 
-        si
+        match camera.mount {
+            Some(mount) => self.update_mount(host, mount, Some(camera.fov))?,
+            None => self.update_info(host, camera.position, Some(camera.fov))?,
+        }
+        Ok(())
+    }
+
+    pub fn hosts(&self) -> &[Host] {
+        &self.hosts
+    }
+
+    /// The camera the client said it has at the host's address, if it was asked while scanning
+    pub fn identity(&self, host: &Host) -> Option<&ClientIdentity> {
+        self.identities.get(&host.address())
     }
 
-    pub fn start_server(&mut self) -> Result<(), StartServerError> {
+    /// Where the connections that weren't for any start came from since the last call,
+    /// either nothing was starting their ip or the start gave up before they arrived
+    pub fn take_dropped_connections(&self) -> Vec<SocketAddr> {
+        std::mem::take(&mut self.dropped.lock().unwrap())
+    }
+
+    pub fn get_server(&self) -> Result<&Host, GetServerError> {
+        find_server(&self.hosts)
+    }
+
+    pub fn start_server(&self) -> Result<(), StartServerError> {
         self.sock.send_to(
             &Into::<Vec<u8>>::into(Command::StartServer { cube: self.cube }),
             (self.get_server()?.ip, MAIN_PORT),
@@ -250,69 +642,379 @@ impl<'o, const BUFFER_SIZE: usize> Organizer<'o, BUFFER_SIZE> {
     }
 
     pub fn start_host<I: OrganizerInterface>(
-        &mut self,
+        &self,
         host: Host,
         interface: I,
-    ) -> Result<(), StartError<I::Error>> {
-        if host.info.host_type == HostType::Server {
-            self.start_server()?;
-            return Ok(());
-        }
+    ) -> Result<(), StartHostError<I::Error>> {
+        self.start_host_cancellable(host, interface, &CancelToken::new())
+    }
+
+    /// Like `start_host`, but gives up once `cancel` is cancelled.
+    /// Several hosts can be started at the same time from different threads.
+    pub fn start_host_cancellable<I: OrganizerInterface>(
+        &self,
+        host: Host,
+        interface: I,
+        cancel: &CancelToken,
+    ) -> Result<(), StartHostError<I::Error>> {
+        use StartPhase::*;
+
+        let calibrated = match host.info.host_type {
+            HostType::Server => {
+                return self
+                    .start_server()
+                    .map_err(|e| StartError::from(e).during(Connect))
+            }
+            HostType::ConfiglessClient => return self.start_configless(host, interface, cancel),
+            HostType::Client { calibrated } => calibrated,
+        };
+
+        let mut s = self.connect(host, cancel).map_err(|e| e.during(Connect))?;
+
+        let images = self
+            .collect_images(&mut s, calibrated, interface, cancel)
+            .map_err(|e| e.during(Images))?;
+
+        let (pos, fov, calib) = match images {
+            ImgLoopState::Calibrating {
+                interface,
+                board,
+                images,
+            } => {
+                let calib = self
+                    .calibrate(board, images, cancel)
+                    .map_err(|e| e.during(Calibration))?;
+
+                let pos = interface
+                    .select_camera_position(calib.horizontal_fov)
+                    .map_err(|e| StartError::Interface(e).during(Config))?;
+
+                (pos, calib.horizontal_fov, Some(calib))
+            }
+
+            ImgLoopState::Showing { interface } => {
+                s.set_read_timeout(Some(self.timeouts.config))
+                    .map_err(|e| StartError::from(e).during(Config))?;
 
-        let addr = (host.ip, MAIN_PORT);
+                let mut fov = [0; size_of::<f64>()];
+                s.read_exact(&mut fov)
+                    .map_err(|e| io_error(e).during(Config))?;
+                let fov = f64::from_be_bytes(fov);
 
-        self.sock.send_to(&[Command::START], addr)?;
+                let pos = interface
+                    .select_camera_position(fov)
+                    .map_err(|e| StartError::Interface(e).during(Config))?;
 
-        // wait for connection on the serversocket
-        let mut s = loop {
-            let (s, a) = self.server_sock.accept()?;
-            if addr.0 == a.ip() {
-                break s;
+                (pos, fov, None)
             }
         };
 
-        let uncalibrated = match host.info {
-            HostInfo {
-                host_type: HostType::Client { calibrated },
-                ..
-            } => !calibrated,
-            HostInfo {
-                host_type: HostType::ConfiglessClient,
-                ..
-            } => false,
+        if cancel.is_cancelled() {
+            return Err(StartError::Cancelled.during(Config));
+        }
+        self.send_config(&mut s, pos, calib.as_ref())
+            .map_err(|e| e.during(Config))?;
+
+        self.place(host, PlacedCamera::new(pos, fov));
+        Ok(())
+    }
+
+    /// Starts an idle calibrated client where it is, solved from the reference board it sees.
+    /// The client is started on the ground plane and then moved to its full 3D pose.
+    pub fn locate_host<I: LocateInterface>(
+        &mut self,
+        host: Host,
+        reference: &ReferenceBoard,
+        interface: I,
+    ) -> Result<PlacedCamera, LocateError<I::Error>> {
+        use StartPhase::*;
+
+        if !matches!(
+            host.info,
             HostInfo {
-                host_type: HostType::Server,
-                ..
-            } => unreachable!(),
+                host_type: HostType::Client { calibrated: true },
+                host_state: HostState::Idle,
+            }
+        ) {
+            return Err(LocateError::NotCalibrated(host));
+        }
+
+        let mut s = self
+            .connect(host, &CancelToken::new())
+            .map_err(|e| e.during(Connect))?;
+
+        let poses = self
+            .locate_from_images(&mut s, reference, &interface)
+            .map_err(|e| e.during(Images))?;
+        if poses.is_empty() {
+            return Err(LocateError::BoardNotFound);
+        }
+
+        let mut translation = [0.; 3];
+        for p in &poses {
+            for (t, v) in translation.iter_mut().zip(p.translation) {
+                *t += v;
+            }
+        }
+        let mount = Pose3::new(
+            translation.map(|v| v / poses.len() as f64),
+            Quaternion::average(poses.iter().map(|p| p.rotation)).unwrap(),
+        );
+
+        let mut fov = [0; size_of::<f64>()];
+        s.set_read_timeout(Some(self.timeouts.config))
+            .and_then(|_| s.read_exact(&mut fov))
+            .map_err(|e| io_error::<I::Error>(e).during(Config))?;
+        let fov = f64::from_be_bytes(fov);
+
+        self.send_config(&mut s, mount.into(), None)
+            .map_err(|e: StartError<I::Error>| e.during(Config))?;
+
+        // the server keeps the height and tilt until the client connects
+        self.update_mount(host, mount, Some(fov))?;
+
+        Ok(PlacedCamera::mounted(mount, fov))
+    }
+
+    /// requests the client's calibration and locates the camera in each image
+    fn locate_from_images<I: LocateInterface>(
+        &self,
+        s: &mut TcpStream,
+        reference: &ReferenceBoard,
+        interface: &I,
+    ) -> Result<Vec<Pose3>, StartError<I::Error>> {
+        s.write_all(&[Command::REQUEST_CALIBRATION])
+            .map_err(io_error)?;
+        let calib = FullCameraInfo::from_be_bytes(s).map_err(io_error)?;
+
+        let board = reference.generate(&self.board)?;
+        let mut poses = vec![];
+
+        loop {
+            s.write_all(&[Command::REQUEST_IMAGE]).map_err(io_error)?;
+
+            let img = get_image(s).map_err(|e| match e {
+                GetImageError::Io(e) => io_error(e),
+                e => e.into(),
+            })?;
+
+            let found = cv::find_board(&img, &board, false)?;
+            let pose = match &found {
+                Some(fb) => reference.locate_camera(&board, fb, &calib.params)?,
+                None => None,
+            };
+            poses.extend(pose);
+
+            interface
+                .show(&img, found.as_ref(), pose)
+                .map_err(StartError::Interface)?;
+            if !interface.more(poses.len()).map_err(StartError::Interface)? {
+                break;
+            }
+        }
+        s.write_all(&[Command::IMAGES_DONE]).map_err(io_error)?;
+
+        Ok(poses)
+    }
+
+    /// Starts the hosts at the same time, each with its own interface
+    pub fn start_hosts<I>(
+        &self,
+        hosts: impl IntoIterator<Item = (Host, I)>,
+        cancel: &CancelToken,
+    ) -> Vec<(Host, Result<(), StartHostError<I::Error>>)>
+    where
+        I: OrganizerInterface + Send,
+        I::Error: Send,
+    {
+        thread::scope(|scope| {
+            let handles: Vec<_> = hosts
+                .into_iter()
+                .map(|(host, interface)| {
+                    (
+                        host,
+                        scope.spawn(move || self.start_host_cancellable(host, interface, cancel)),
+                    )
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|(host, h)| (host, h.join().expect("start thread panicked")))
+                .collect()
+        })
+    }
+
+    /// sends `START` and waits for the client to connect
+    fn connect<E>(&self, host: Host, cancel: &CancelToken) -> Result<TcpStream, StartError<E>> {
+        let till = Instant::now() + self.timeouts.connect;
+
+        // the cameras of a client connect from the same ip, they can't be told apart
+        let _starting = self.claim_ip(host.ip, till, cancel)?;
+
+        self.sock.send_to(&[Command::START], host.address())?;
+
+        let s = loop {
+            if cancel.is_cancelled() {
+                return Err(StartError::Cancelled);
+            }
+            if Instant::now() >= till {
+                return Err(StartError::TimedOut);
+            }
+
+            let mut accepted = self.accepted.lock().unwrap();
+            // the start they were for gave up
+            accepted.retain(|(s, at)| {
+                let stale = at.elapsed() >= self.timeouts.connect;
+                if stale {
+                    if let Ok(a) = s.peer_addr() {
+                        self.dropped.lock().unwrap().push(a);
+                    }
+                }
+                !stale
+            });
+
+            let ours = accepted
+                .iter()
+                .position(|(s, _)| matches!(s.peer_addr(), Ok(a) if a.ip() == host.ip));
+            if let Some(i) = ours {
+                break accepted.swap_remove(i).0;
+            }
+
+            match self.server_sock.accept() {
+                Ok((s, a)) if a.ip() == host.ip => break s,
+
+                // hand it over to the start it belongs to, drop it if there's none
+                Ok((s, a)) => {
+                    let starting = self.starting.lock().unwrap();
+                    if matches!(a.ip(), IpAddr::V4(ip) if starting.contains(&ip)) {
+                        accepted.push((s, Instant::now()));
+                    } else {
+                        self.dropped.lock().unwrap().push(a);
+                    }
+                }
+
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    drop(accepted);
+                    thread::sleep(POLL_INTERVAL);
+                }
+                Err(e) => Err(e)?,
+            }
         };
 
-        let mut uncalibrated: ImgLoopState<
-            <I as OrganizerInterface>::CalibrationInterface,
-            <I as OrganizerInterface>::ImageStreamInterface,
-        > = if uncalibrated {
+        s.set_nonblocking(false)?;
+        s.set_read_timeout(Some(self.timeouts.image))?;
+        s.set_write_timeout(Some(self.timeouts.config))?;
+
+        Ok(s)
+    }
+
+    /// calibrates on another thread so that it can time out and be cancelled
+    fn calibrate<E>(
+        &self,
+        board: CharucoBoard,
+        images: Vec<Mat>,
+        cancel: &CancelToken,
+    ) -> Result<FullCameraInfo, StartError<E>> {
+        let till = Instant::now() + self.timeouts.calibration;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let res = images[0]
+                .size()
+                .and_then(|size| cv::calibrate(&board, &images, size));
+            let _ = tx.send(res);
+        });
+
+        loop {
+            if cancel.is_cancelled() {
+                return Err(StartError::Cancelled);
+            }
+            if Instant::now() >= till {
+                return Err(StartError::TimedOut);
+            }
+
+            match rx.recv_timeout(POLL_INTERVAL) {
+                Ok(res) => return Ok(res?),
+                Err(std::sync::mpsc::RecvTimeoutError::Timeout) => (),
+                Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(StartError::CalibrationCrashed)
+                }
+            }
+        }
+    }
+
+    /// waits until no other client with the same ip is being started
+    fn claim_ip<E>(
+        &self,
+        ip: Ipv4Addr,
+        till: Instant,
+        cancel: &CancelToken,
+    ) -> Result<StartingGuard<'_, 'o, BUFFER_SIZE>, StartError<E>> {
+        let mut starting = self.starting.lock().unwrap();
+
+        while starting.contains(&ip) {
+            if cancel.is_cancelled() {
+                return Err(StartError::Cancelled);
+            }
+            let now = Instant::now();
+            if now >= till {
+                return Err(StartError::TimedOut);
+            }
+
+            starting = self
+                .starting_changed
+                .wait_timeout(starting, POLL_INTERVAL.min(till - now))
+                .unwrap()
+                .0;
+        }
+
+        starting.push(ip);
+        Ok(StartingGuard {
+            organizer: self,
+            ip,
+        })
+    }
+
+    fn collect_images<I: OrganizerInterface>(
+        &self,
+        s: &mut TcpStream,
+        calibrated: bool,
+        interface: I,
+        cancel: &CancelToken,
+    ) -> Result<ImgLoopState<I::CalibrationInterface, I::ImageStreamInterface>, StartError<I::Error>>
+    {
+        let mut state = if calibrated {
+            ImgLoopState::Showing {
+                interface: interface
+                    .start_image_stream()
+                    .map_err(StartError::Interface)?,
+            }
+        } else {
             let interface = interface
                 .start_calibration()
                 .map_err(StartError::Interface)?;
             let (width, height) = interface.get_board_size().map_err(StartError::Interface)?;
             ImgLoopState::Calibrating {
-                board: cv::generate_board(width, height)?,
+                board: cv::generate_board(width, height, &self.board)?,
                 images: vec![],
                 interface,
             }
-        } else {
-            ImgLoopState::Showing {
-                interface: interface
-                    .start_image_stream()
-                    .map_err(StartError::Interface)?,
-            }
         };
 
         loop {
-            s.write_all(&[Command::REQUEST_IMAGE])?;
+            if cancel.is_cancelled() {
+                return Err(StartError::Cancelled);
+            }
+
+            s.write_all(&[Command::REQUEST_IMAGE]).map_err(io_error)?;
 
-            let img = self.get_image(&mut s)?;
+            let img = get_image(s).map_err(|e| match e {
+                GetImageError::Io(e) => io_error(e),
+                e => e.into(),
+            })?;
 
-            match &mut uncalibrated {
+            match &mut state {
                 ImgLoopState::Calibrating {
                     board,
                     images,
@@ -345,53 +1047,108 @@ impl<'o, const BUFFER_SIZE: usize> Organizer<'o, BUFFER_SIZE> {
                 }
             }
         }
-        s.write_all(&[Command::IMAGES_DONE])?;
+        s.write_all(&[Command::IMAGES_DONE]).map_err(io_error)?;
 
-        let server_ip = self.get_server()?.ip.to_string();
-        let ip_bytes = server_ip.as_bytes();
-        let ip_len = ip_bytes.len() as u16;
-
-        let (pos, calib) = match uncalibrated {
-            ImgLoopState::Calibrating {
-                interface,
-                board,
-                images,
-            } => {
-                let calib = cv::calibrate(&board, &images, images[0].size()?)?;
+        Ok(state)
+    }
 
-                let pos = interface
-                    .select_camera_position(calib.horizontal_fov)
-                    .map_err(StartError::Interface)?;
+    fn send_config<E>(
+        &self,
+        s: &mut TcpStream,
+        pos: Position,
+        calib: Option<&FullCameraInfo>,
+    ) -> Result<(), StartError<E>> {
+        let config = config_bytes(pos, self.get_server()?.ip, calib, self.cube, &self.board);
+        s.write_all(&config).map_err(io_error)
+    }
 
-                (pos, Some(calib))
-            }
+    /// Configless clients only get the server's ip,
+    /// they answer with the fov they connected to the server with
+    fn start_configless<I: OrganizerInterface>(
+        &self,
+        host: Host,
+        interface: I,
+        cancel: &CancelToken,
+    ) -> Result<(), StartHostError<I::Error>> {
+        let server = self
+            .get_server()
+            .map_err(|e| StartError::from(e).during(StartPhase::Connect))?
+            .ip;
+
+        let fov = self
+            .wait_for_configless(host, server, cancel)
+            .map_err(|e| e.during(StartPhase::Connect))?;
+
+        let position = interface
+            .start_image_stream()
+            .map_err(StartError::Interface)
+            .and_then(|i| i.select_camera_position(fov).map_err(StartError::Interface))
+            .map_err(|e| e.during(StartPhase::Config))?;
+
+        // the client connected with a placeholder position
+        self.sock
+            .send_to(
+                &Into::<Vec<u8>>::into(Command::InfoUpdate {
+                    client_ip: &host.address().to_string(),
+                    position,
+                    fov: None,
+                    mount: None,
+                }),
+                (server, MAIN_PORT),
+            )
+            .map_err(|e| StartError::from(e).during(StartPhase::Config))?;
+
+        self.place(host, PlacedCamera::new(position, fov));
+        Ok(())
+    }
 
-            ImgLoopState::Showing { interface } => {
-                let fov = &mut self.buffer[..size_of::<f64>()];
-                s.read_exact(fov)?;
-                let fov = f64::from_be_bytes(fov.try_into().unwrap());
+    fn wait_for_configless<E>(
+        &self,
+        host: Host,
+        server: Ipv4Addr,
+        cancel: &CancelToken,
+    ) -> Result<f64, StartError<E>> {
+        // the answers arrive on the same socket, so one start listens at a time
+        let _listening = self.configless.lock().unwrap();
 
-                let pos = interface
-                    .select_camera_position(fov)
-                    .map_err(StartError::Interface)?;
+        self.sock.send_to(
+            &Into::<Vec<u8>>::into(Command::StartConfigless {
+                ip: &server.to_string(),
+            }),
+            host.address(),
+        )?;
 
-                (pos, None)
+        let till = Instant::now() + self.timeouts.connect;
+        let mut buf = [0; 64];
+        loop {
+            if cancel.is_cancelled() {
+                return Err(StartError::Cancelled);
+            }
+            if Instant::now() >= till {
+                return Err(StartError::TimedOut);
             }
-        };
 
-        s.write_all(pos.x.to_be_bytes().as_slice())?;
-        s.write_all(pos.y.to_be_bytes().as_slice())?;
-        s.write_all(pos.rotation.to_be_bytes().as_slice())?;
-        s.write_all(ip_len.to_be_bytes().as_slice())?;
-        s.write_all(ip_bytes)?;
+            let (len, addr) = match self.sock.recv_from(&mut buf) {
+                Ok(r) => r,
+                // windows reports read timeouts as `TimedOut`
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue
+                }
+                Err(e) => Err(e)?,
+            };
 
-        if let Some(calib) = calib {
-            s.write_all(calib.to_be_bytes().as_slice())?;
+            if addr != host.address().into() {
+                continue;
+            }
+            if let Ok(Command::Connect { fov, .. }) = buf[..len].try_into() {
+                return Ok(fov);
+            }
         }
-
-        s.write_all(&self.cube.map(u8::to_be))?;
-
-        Ok(())
     }
 
     pub fn stop_host(&mut self, host: Host) -> Result<(), StopError> {
@@ -405,7 +1162,7 @@ impl<'o, const BUFFER_SIZE: usize> Organizer<'o, BUFFER_SIZE> {
             return Err(StopError::NotRunning(host));
         }
 
-        self.sock.send_to(&[Command::STOP], (host.ip, MAIN_PORT))?;
+        self.sock.send_to(&[Command::STOP], host.address())?;
         self.hosts
             .remove(self.hosts.iter().position(|h| *h == host).unwrap());
 
@@ -424,6 +1181,20 @@ impl<'o, const BUFFER_SIZE: usize> Organizer<'o, BUFFER_SIZE> {
         'loopy: while Instant::now() < till {
             let addr = match self.sock.recv_from(self.buffer) {
                 Ok((1, addr)) => addr,
+                Ok((len, SocketAddr::V4(addr))) => {
+                    if let Ok(Command::Identity {
+                        device,
+                        calibration,
+                    }) = self.buffer[..len].try_into()
+                    {
+                        let identity = ClientIdentity {
+                            device: device.to_string(),
+                            calibration: calibration.to_string(),
+                        };
+                        self.identities.insert(addr, identity);
+                    }
+                    continue;
+                }
                 Ok(_) => continue,
 
                 Err(e) => match e.kind() {
@@ -439,17 +1210,26 @@ impl<'o, const BUFFER_SIZE: usize> Organizer<'o, BUFFER_SIZE> {
                 continue 'loopy;
             };
 
+            // the camera at the address may have changed since the last scan
+            if matches!(info.host_type, HostType::Client { .. }) {
+                self.sock.send_to(&[Command::IDENTIFY], addr)?;
+            }
+
             let h = self
                 .hosts
                 .iter_mut()
                 .zip(hit_hosts.iter_mut())
-                .find(|(h, _)| h.ip == ip);
+                .find(|(h, _)| h.ip == ip && h.port == addr.port());
 
             if let Some((h, hit)) = h {
                 *hit = true;
                 h.info = info;
             } else {
-                self.hosts.push(Host { info, ip });
+                self.hosts.push(Host {
+                    info,
+                    ip,
+                    port: addr.port(),
+                });
             }
         }
 
@@ -485,18 +1265,106 @@ impl<'o, const BUFFER_SIZE: usize> Organizer<'o, BUFFER_SIZE> {
 
         Ok(())
     }
+}
+
+/// removes the ip from the ones being started when dropped
+struct StartingGuard<'o, 'b, const BUFFER_SIZE: usize> {
+    organizer: &'o Organizer<'b, BUFFER_SIZE>,
+    ip: Ipv4Addr,
+}
+
+impl<const BUFFER_SIZE: usize> Drop for StartingGuard<'_, '_, BUFFER_SIZE> {
+    fn drop(&mut self) {
+        self.organizer
+            .starting
+            .lock()
+            .unwrap()
+            .retain(|ip| *ip != self.ip);
+        self.organizer.starting_changed.notify_all();
+    }
+}
+
+/// the one server among the hosts
+fn find_server<'h>(hosts: impl IntoIterator<Item = &'h Host>) -> Result<&'h Host, GetServerError> {
+    let mut si = Err(GetServerError::NoServer);
+
+    for h in hosts {
+        if matches!(
+            h.info,
+            HostInfo {
+                host_type: HostType::Server,
+                host_state: HostState::Idle | HostState::Running
+            },
+        ) {
+            si = match si {
+                Ok(_) => Err(GetServerError::Multiple(1)),
+                Err(GetServerError::NoServer) => Ok(h),
+                Err(GetServerError::Multiple(n)) => Err(GetServerError::Multiple(n + 1)),
+            };
+        }
+    }
 
-    fn get_image(&mut self, r: &mut impl Read) -> Result<Mat, GetImageError> {
-        r.read_exact(&mut self.buffer[..size_of::<u64>()])?;
-        let len = u64::from_be_bytes(self.buffer[..size_of::<u64>()].try_into().unwrap()) as usize;
+    si
+}
+
+/// what the client gets at the end of starting it
+fn config_bytes(
+    pos: Position,
+    server: Ipv4Addr,
+    calib: Option<&FullCameraInfo>,
+    cube: [u16; 4],
+    board: &BoardConfig,
+) -> Vec<u8> {
+    let server_ip = server.to_string();
+    let ip_bytes = server_ip.as_bytes();
+    let ip_len = ip_bytes.len() as u16;
+
+    let mut config = [
+        pos.x.to_be_bytes().as_slice(),
+        pos.y.to_be_bytes().as_slice(),
+        pos.rotation.to_be_bytes().as_slice(),
+        ip_len.to_be_bytes().as_slice(),
+        ip_bytes,
+    ]
+    .concat();
+
+    if let Some(calib) = calib {
+        config.extend(calib.to_be_bytes());
+    }
 
-        let mut buffer = opencv::core::Vector::from_elem(0, len);
+    config.extend(cube.map(u16::to_be_bytes).concat());
+    config.extend(board.to_be_bytes());
 
-        r.read_exact(&mut buffer.as_mut_slice()[..len])?;
+    config
+}
 
-        Ok(imgcodecs::imdecode(&buffer, imgcodecs::IMREAD_COLOR)?)
+/// io errors of sockets with timeouts
+fn io_error<E>(e: std::io::Error) -> StartError<E> {
+    match e.kind() {
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => StartError::TimedOut,
+        _ => StartError::Io(e),
     }
 }
 
+fn get_image(r: &mut impl Read) -> Result<Mat, GetImageError> {
+    let mut len = [0; size_of::<u64>()];
+    r.read_exact(&mut len)?;
+    let len = u64::from_be_bytes(len);
+    if len > MAX_IMAGE_SIZE {
+        return Err(GetImageError::TooLarge(len));
+    }
+    let len = len as usize;
+
+    let mut buffer = opencv::core::Vector::from_elem(0, len);
+
+    r.read_exact(&mut buffer.as_mut_slice()[..len])?;
+
+    Ok(imgcodecs::imdecode(&buffer, imgcodecs::IMREAD_COLOR)?)
+}
+
+/// The largest encoded image accepted from a client
+const MAX_IMAGE_SIZE: u64 = 64 * 1024 * 1024;
+
 const TIMEOUT_DURATION: Duration = Duration::from_millis(500);
 const WAIT_DURATION: Duration = Duration::from_millis(TIMEOUT_DURATION.as_millis() as u64 * 4);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
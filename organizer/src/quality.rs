@@ -0,0 +1,297 @@
+use camloc_common::{cv::FoundBoard, pose::Quaternion};
+use opencv::{
+    calib3d,
+    core::{self, Mat},
+    imgproc,
+    objdetect::CharucoBoard,
+    prelude::*,
+    types,
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error as ThisError;
+
+/// What calibration images have to be like and when there are enough of them
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct QualityThresholds {
+    /// charuco corners an image needs
+    pub min_corners: usize,
+    /// the part of the image the board has to cover
+    pub min_area: f64,
+    /// variance of the laplacian of the board, lower is blurrier
+    pub min_sharpness: f64,
+    /// how differently the board has to be tilted than in every kept image,
+    /// unless it's somewhere new in the image (radians)
+    pub min_tilt_difference: f64,
+    /// how much the board counts as tilted (radians)
+    pub min_tilt: f64,
+    /// tilted images to keep at least
+    pub min_tilted_images: usize,
+    /// images to keep at least
+    pub min_images: usize,
+    /// the part of the image the kept boards have to cover together
+    pub min_coverage: f64,
+    /// images to look at before giving up
+    pub max_images: usize,
+}
+
+impl Default for QualityThresholds {
+    fn default() -> Self {
+        Self {
+            min_corners: 12,
+            min_area: 0.02,
+            min_sharpness: 50.,
+            min_tilt_difference: 10f64.to_radians(),
+            min_tilt: 20f64.to_radians(),
+            min_tilted_images: 3,
+            min_images: 15,
+            min_coverage: 0.7,
+            max_images: 200,
+        }
+    }
+}
+
+/// The scores of a calibration image
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ImageQuality {
+    pub corners: usize,
+    /// the part of the image the board covers
+    pub area: f64,
+    /// variance of the laplacian of the board
+    pub sharpness: f64,
+    /// normal of the board in the camera's frame, roughly (the camera isn't calibrated yet),
+    /// `None` if too few corners were found
+    pub normal: Option<[f64; 3]>,
+}
+
+impl ImageQuality {
+    /// angle between the board's normal and the optical axis
+    pub fn tilt(&self) -> Option<f64> {
+        self.normal.map(|n| n[2].abs().clamp(0., 1.).acos())
+    }
+}
+
+impl std::fmt::Display for ImageQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} corners, {:.0}% of the image, sharpness {:.0}",
+            self.corners,
+            self.area * 100.,
+            self.sharpness
+        )?;
+        if let Some(tilt) = self.tilt() {
+            write!(f, ", tilted {:.0}°", tilt.to_degrees())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(ThisError, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    #[error("too few corners")]
+    TooFewCorners,
+
+    #[error("the board is too small")]
+    TooSmall,
+
+    #[error("too blurry")]
+    Blurry,
+
+    #[error("too similar to the kept images")]
+    TooSimilar,
+}
+
+/// The image is split into `COVERAGE_GRID`² cells to measure how much the boards covered
+const COVERAGE_GRID: usize = 10;
+
+/// Decides which calibration images to keep and when there are enough,
+/// instead of someone looking at them
+pub struct QualityGate {
+    board: CharucoBoard,
+    thresholds: QualityThresholds,
+    tried: usize,
+    kept: Vec<ImageQuality>,
+    /// cells of the image a kept board had corners in
+    covered: [[bool; COVERAGE_GRID]; COVERAGE_GRID],
+}
+
+impl QualityGate {
+    pub fn new(board: CharucoBoard, thresholds: QualityThresholds) -> Self {
+        Self {
+            board,
+            thresholds,
+            tried: 0,
+            kept: vec![],
+            covered: Default::default(),
+        }
+    }
+
+    pub fn assess(&self, img: &Mat, found: &FoundBoard) -> opencv::Result<ImageQuality> {
+        let corners = found.corners();
+        let size = img.size()?;
+
+        let mut hull = types::VectorOfPoint2f::new();
+        imgproc::convex_hull(corners, &mut hull, false, true)?;
+        let area = imgproc::contour_area(&hull, false)? / size.area() as f64;
+
+        // the laplacian of a sharp board has strong edges
+        let roi = imgproc::bounding_rect(corners)? & core::Rect::new(0, 0, size.width, size.height);
+        let mut gray = Mat::default();
+        imgproc::cvt_color(&Mat::roi(img, roi)?, &mut gray, imgproc::COLOR_BGR2GRAY, 0)?;
+        let mut laplacian = Mat::default();
+        imgproc::laplacian(
+            &gray,
+            &mut laplacian,
+            core::CV_64F,
+            1,
+            1.,
+            0.,
+            core::BORDER_DEFAULT,
+        )?;
+        let (mut mean, mut deviation) = (Mat::default(), Mat::default());
+        core::mean_std_dev(&laplacian, &mut mean, &mut deviation, &core::no_array())?;
+        let sharpness = deviation.at::<f64>(0)?.powi(2);
+
+        Ok(ImageQuality {
+            corners: corners.len(),
+            area,
+            sharpness,
+            normal: self.normal(found, size)?,
+        })
+    }
+
+    /// the normal of the board with a guessed camera matrix
+    fn normal(&self, found: &FoundBoard, size: core::Size) -> opencv::Result<Option<[f64; 3]>> {
+        // the corners of a single row are on a line, they don't give a pose
+        if found.ids().len() < 6 {
+            return Ok(None);
+        }
+
+        let mut object_points = types::VectorOfPoint3f::new();
+        let mut image_points = types::VectorOfPoint2f::new();
+        self.board.match_image_points(
+            found.corners(),
+            found.ids(),
+            &mut object_points,
+            &mut image_points,
+        )?;
+
+        let (w, h) = (size.width as f64, size.height as f64);
+        let f = w.max(h);
+        let camera_matrix =
+            Mat::from_slice_rows_cols(&[f, 0., 0.5 * w, 0., f, 0.5 * h, 0., 0., 1.], 3, 3)?;
+
+        let (mut rvec, mut tvec) = (Mat::default(), Mat::default());
+        if !calib3d::solve_pnp(
+            &object_points,
+            &image_points,
+            &camera_matrix,
+            &core::no_array(),
+            &mut rvec,
+            &mut tvec,
+            false,
+            calib3d::SOLVEPNP_ITERATIVE,
+        )? {
+            return Ok(None);
+        }
+
+        let rvec = [
+            *rvec.at::<f64>(0)?,
+            *rvec.at::<f64>(1)?,
+            *rvec.at::<f64>(2)?,
+        ];
+        Ok(Some(
+            Quaternion::from_rotation_vector(rvec).rotate([0., 0., 1.]),
+        ))
+    }
+
+    /// Scores the image and keeps it if it's good and adds something new
+    pub fn consider(
+        &mut self,
+        img: &Mat,
+        found: &FoundBoard,
+    ) -> opencv::Result<(ImageQuality, Result<(), Rejection>)> {
+        self.tried += 1;
+
+        let quality = self.assess(img, found)?;
+        let t = &self.thresholds;
+
+        let verdict = if quality.corners < t.min_corners {
+            Err(Rejection::TooFewCorners)
+        } else if quality.area < t.min_area {
+            Err(Rejection::TooSmall)
+        } else if quality.sharpness < t.min_sharpness {
+            Err(Rejection::Blurry)
+        } else {
+            let size = img.size()?;
+            let cells: Vec<_> = found
+                .corners()
+                .iter()
+                .map(|c| {
+                    let x = (c.x / size.width as f32 * COVERAGE_GRID as f32) as usize;
+                    let y = (c.y / size.height as f32 * COVERAGE_GRID as f32) as usize;
+                    (x.min(COVERAGE_GRID - 1), y.min(COVERAGE_GRID - 1))
+                })
+                .collect();
+
+            let somewhere_new = cells.iter().any(|(x, y)| !self.covered[*y][*x]);
+            let tilted_differently = quality.normal.is_some_and(|n| {
+                self.kept.iter().filter_map(|k| k.normal).all(|k| {
+                    let cos = (n[0] * k[0] + n[1] * k[1] + n[2] * k[2]).abs();
+                    cos.clamp(0., 1.).acos() >= t.min_tilt_difference
+                })
+            });
+
+            if self.kept.is_empty() || somewhere_new || tilted_differently {
+                for (x, y) in cells {
+                    self.covered[y][x] = true;
+                }
+                self.kept.push(quality);
+                Ok(())
+            } else {
+                Err(Rejection::TooSimilar)
+            }
+        };
+
+        Ok((quality, verdict))
+    }
+
+    /// Counts an image the board wasn't found in
+    pub fn missed(&mut self) {
+        self.tried += 1;
+    }
+
+    pub fn tried(&self) -> usize {
+        self.tried
+    }
+
+    pub fn kept(&self) -> usize {
+        self.kept.len()
+    }
+
+    /// The part of the image the kept boards covered together
+    pub fn coverage(&self) -> f64 {
+        let covered = self.covered.iter().flatten().filter(|c| **c).count();
+        covered as f64 / (COVERAGE_GRID * COVERAGE_GRID) as f64
+    }
+
+    /// Enough images of the board were kept, all over the image and tilted in different ways
+    pub fn is_sufficient(&self) -> bool {
+        let t = &self.thresholds;
+        let tilted = self
+            .kept
+            .iter()
+            .filter(|k| k.tilt().is_some_and(|tilt| tilt >= t.min_tilt))
+            .count();
+
+        self.kept.len() >= t.min_images
+            && self.coverage() >= t.min_coverage
+            && tilted >= t.min_tilted_images
+    }
+
+    /// Too many images were looked at without it getting sufficient
+    pub fn gave_up(&self) -> bool {
+        self.tried >= self.thresholds.max_images && !self.is_sufficient()
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{
+    quality::{QualityGate, QualityThresholds},
+    CalibrationInterface, ImageStreamInterface, OrganizerInterface,
+};
+use camloc_common::{
+    cv::{self, BoardConfig, FoundBoard},
+    Position,
+};
+use opencv::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, time::Duration};
+use thiserror::Error as ThisError;
+
+#[derive(ThisError, Debug)]
+pub enum ScriptError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid config: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("{0}")]
+    Dictionary(String),
+
+    #[error("opencv error: {0}")]
+    OpenCV(#[from] opencv::Error),
+
+    #[error(
+        "gave up after {tried} images, kept {kept} covering {:.0}% of the image",
+        .coverage * 100.
+    )]
+    GaveUp {
+        tried: usize,
+        kept: usize,
+        coverage: f64,
+    },
+}
+
+/// Everything needed to bring up a deployment without anyone at the organizer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScriptConfig {
+    /// the layout file the clients are placed by (see `Organizer::layout`)
+    pub layout: String,
+    /// the marker dictionary of the cube and the calibration board, e.g. `4x4_50`
+    pub dictionary: String,
+    /// side length of a printed calibration board square (m)
+    pub square_length: f32,
+    /// side length of a printed calibration board marker (m)
+    pub marker_length: f32,
+    /// squares of the calibration board
+    pub board_width: u8,
+    pub board_height: u8,
+    pub calibration: QualityThresholds,
+    /// how long to look for the server and the clients of the layout (seconds)
+    pub discovery_timeout: f64,
+}
+
+impl Default for ScriptConfig {
+    fn default() -> Self {
+        let board = BoardConfig::default();
+        Self {
+            layout: "layout.json".to_string(),
+            dictionary: board.dictionary.to_string(),
+            square_length: board.square_length,
+            marker_length: board.marker_length,
+            board_width: 5,
+            board_height: 7,
+            calibration: QualityThresholds::default(),
+            discovery_timeout: 30.,
+        }
+    }
+}
+
+impl ScriptConfig {
+    /// Reads the config from a json file, missing fields are the defaults
+    pub fn load(path: impl AsRef<std::path::Path>) -> Result<Self, ScriptError> {
+        let file = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+    }
+
+    pub fn board(&self) -> Result<BoardConfig, ScriptError> {
+        Ok(BoardConfig {
+            dictionary: self.dictionary.parse().map_err(ScriptError::Dictionary)?,
+            square_length: self.square_length,
+            marker_length: self.marker_length,
+        })
+    }
+
+    pub fn discovery_timeout(&self) -> Duration {
+        Duration::from_secs_f64(self.discovery_timeout)
+    }
+}
+
+/// Calibrates a client by the thresholds of the config and places it at `position`
+pub struct ScriptedInterface<'c> {
+    config: &'c ScriptConfig,
+    position: Position,
+    /// made with the first image
+    gate: RefCell<Option<QualityGate>>,
+}
+
+impl<'c> ScriptedInterface<'c> {
+    pub fn new(config: &'c ScriptConfig, position: Position) -> Self {
+        Self {
+            config,
+            position,
+            gate: RefCell::default(),
+        }
+    }
+
+    /// runs `f` on the gate, fails once too many images were looked at
+    fn with_gate<T>(&self, f: impl FnOnce(&mut QualityGate) -> T) -> Result<T, ScriptError> {
+        let mut gate = self.gate.borrow_mut();
+        let gate = match &mut *gate {
+            Some(gate) => gate,
+            None => {
+                let (width, height) = (self.config.board_width, self.config.board_height);
+                let board = cv::generate_board(width, height, &self.config.board()?)?;
+                gate.insert(QualityGate::new(board, self.config.calibration))
+            }
+        };
+
+        if gate.gave_up() {
+            return Err(ScriptError::GaveUp {
+                tried: gate.tried(),
+                kept: gate.kept(),
+                coverage: gate.coverage(),
+            });
+        }
+        Ok(f(gate))
+    }
+}
+
+impl OrganizerInterface for ScriptedInterface<'_> {
+    type CalibrationInterface = Self;
+    type ImageStreamInterface = Self;
+    type Error = ScriptError;
+
+    fn start_image_stream(self) -> Result<Self::ImageStreamInterface, Self::Error> {
+        Ok(self)
+    }
+
+    fn start_calibration(self) -> Result<Self::CalibrationInterface, Self::Error> {
+        Ok(self)
+    }
+}
+
+impl CalibrationInterface for ScriptedInterface<'_> {
+    type Parent = Self;
+
+    fn get_board_size(&self) -> Result<(u8, u8), ScriptError> {
+        Ok((self.config.board_width, self.config.board_height))
+    }
+
+    fn keep_image(&self, img: &Mat, board: &FoundBoard) -> Result<bool, ScriptError> {
+        let (_, verdict) = self.with_gate(|gate| gate.consider(img, board))??;
+        Ok(verdict.is_ok())
+    }
+
+    fn board_not_found(&self, _img: &Mat) -> Result<(), ScriptError> {
+        self.with_gate(QualityGate::missed)
+    }
+
+    fn more(&self) -> Result<bool, ScriptError> {
+        self.with_gate(|gate| !gate.is_sufficient())
+    }
+
+    fn select_camera_position(self, _fov: f64) -> Result<Position, ScriptError> {
+        Ok(self.position)
+    }
+}
+
+/// calibrated clients are placed right away
+impl ImageStreamInterface for ScriptedInterface<'_> {
+    type Parent = Self;
+
+    fn show(&self, _img: &Mat) -> Result<(), ScriptError> {
+        Ok(())
+    }
+
+    fn more(&self) -> Result<bool, ScriptError> {
+        Ok(false)
+    }
+
+    fn select_camera_position(self, _fov: f64) -> Result<Position, ScriptError> {
+        Ok(self.position)
+    }
+}
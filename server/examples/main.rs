@@ -1,7 +1,8 @@
 use anyhow::Result;
-use camloc_common::{yes_no_choice, Position};
+use camloc_common::{get_from_stdin, yes_no_choice, Position};
 use camloc_server::service::LocationServiceTrait;
 use camloc_server::{
+    refine::RefineOptions,
     service::{self, Event},
     PlacedCamera,
 };
@@ -26,7 +27,7 @@ fn main() {
 
 #[cfg(feature = "serial-compass")]
 async fn get_compass() -> Result<camloc_server::compass::serial::SerialCompass> {
-    use camloc_common::{choice, get_from_stdin};
+    use camloc_common::choice;
     use camloc_server::compass::serial::SerialCompass;
     use tokio_serial::{SerialPortBuilderExt, SerialPortType};
 
@@ -137,12 +138,25 @@ async fn get_compass() -> Result<camloc_server::compass::serial::SerialCompass>
 #[tokio::main]
 async fn run() -> Result<()> {
     let service = service::Builder::new();
+    let service = match get_from_stdin::<f64>(
+        "Enter marker size (leave empty to disable single camera fixes): ",
+    ) {
+        Ok(size) => service.with_marker_size(size),
+        Err(_) => service,
+    };
 
     #[cfg(feature = "serial-compass")]
     let service = service.with_compass(get_compass().await?);
 
     let service = service.start().await?;
 
+    if yes_no_choice(
+        "Refine the camera placements? (once the clients are started)",
+        false,
+    ) {
+        refine_cameras(&service).await?;
+    }
+
     let cancell_parent = CancellationToken::new();
     let cancell = cancell_parent.child_token();
     spawn(async move {
@@ -172,9 +186,13 @@ async fn run() -> Result<()> {
                     spawn(on_info_update(address, camera));
                 }
 
-                Event::PositionUpdate(position) => {
+                Event::PositionUpdate(position, _) => {
                     spawn(on_position(position));
                 }
+
+                Event::PoseUpdate(pose) => println!("{pose}"),
+
+                Event::Telemetry(address, telemetry) => println!("{address}: {telemetry}"),
             }
         }
     } else {
@@ -193,6 +211,35 @@ async fn run() -> Result<()> {
     Ok(())
 }
 
+async fn refine_cameras(service: &impl LocationServiceTrait) -> Result<()> {
+    service.start_recording().await;
+    get_from_stdin::<String>("Move the cube around where the cameras see it, then press enter")?;
+
+    let Some(refinement) = service.refine_cameras(RefineOptions::default()).await else {
+        println!("The cameras never saw the cube together");
+        return Ok(());
+    };
+
+    println!(
+        "Refined from {} samples, the rays miss by {:.2}° instead of {:.2}°",
+        refinement.samples,
+        refinement.residual_after.to_degrees(),
+        refinement.residual_before.to_degrees(),
+    );
+    for (address, camera) in &refinement.cameras {
+        println!(
+            "  {address}: {} fov {:.2}°",
+            camera.position,
+            camera.fov.to_degrees()
+        );
+    }
+
+    if yes_no_choice("Apply the refined placements?", true) {
+        service.apply_refinement(&refinement).await?;
+    }
+    Ok(())
+}
+
 async fn on_position(position: Position) -> tokio::io::Result<()> {
     println!("{position}");
 
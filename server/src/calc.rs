@@ -1,16 +1,21 @@
-use camloc_common::{hosts::ClientData, Position};
+use camloc_common::{
+    hosts::{ClientData, MarkerPose},
+    pose::Quaternion,
+    Pose3, Position,
+};
 
-use crate::{MotionHint, PlacedCamera};
+use crate::{CubePose, FixKind, MotionHint, PlacedCamera};
 
-#[allow(clippy::needless_range_loop)]
+#[allow(clippy::needless_range_loop, clippy::too_many_arguments)]
 pub fn calculate_position(
     min_camera_angle_diff: f64,
     data: &[(Option<ClientData>, PlacedCamera)],
     motion_data: Option<MotionData>,
     compass_data: Option<f64>,
     last_position: Option<Position>,
-    _cube: [u8; 4],
-) -> Option<Position> {
+    marker_size: Option<f64>,
+    _cube: [u16; 4],
+) -> Option<(Position, FixKind)> {
     let c = data.len();
 
     let mut tangents = vec![None; c];
@@ -18,13 +23,16 @@ pub fn calculate_position(
     let mut lines = 0u32;
     for i in 0..c {
         if let (Some(data), camera) = data[i] {
-            let tan = (camera.position.rotation + (camera.fov * (0.5 - data.x_position))).tan();
+            let tan = get_ray_angle(&data, &camera).tan();
             tangents[i] = Some(tan);
             lines += 1;
         }
     }
     if lines < 2 {
-        return None;
+        let (x, y) = get_ranged_position(data, marker_size?)?;
+        let r = get_rotation(x, y, motion_data, compass_data, last_position);
+
+        return Some((Position::new(x, y, r), FixKind::Ranged));
     }
 
     let (mut x, mut y) = (0., 0.);
@@ -61,11 +69,83 @@ pub fn calculate_position(
         }
     }
 
+    // every camera pair was too close to parallel
+    if points == 0 {
+        if let Some((x, y)) = marker_size.and_then(|s| get_ranged_position(data, s)) {
+            let r = get_rotation(x, y, motion_data, compass_data, last_position);
+
+            return Some((Position::new(x, y, r), FixKind::Ranged));
+        }
+    }
+
     let points = points as f64;
 
     x /= points;
     y /= points;
 
+    let r = get_rotation(x, y, motion_data, compass_data, last_position);
+
+    Some((Position::new(x, y, r), FixKind::Triangulated))
+}
+
+/// The bearing a camera with `to_fov` would have measured,
+/// if it was measured assuming `from_fov`
+pub fn rescale_bearing(bearing: f64, from_fov: f64, to_fov: f64) -> f64 {
+    if from_fov == to_fov || from_fov.is_nan() {
+        return bearing;
+    }
+    (bearing.tan() * (0.5 * to_fov).tan() / (0.5 * from_fov).tan()).atan()
+}
+
+/// The world angle of the ray pointing from the camera towards the marker
+fn get_ray_angle(data: &ClientData, camera: &PlacedCamera) -> f64 {
+    camera.position.rotation + data.bearing
+}
+
+/// Averages the single camera estimates of every camera
+/// that reported the apparent height of the marker
+fn get_ranged_position(
+    data: &[(Option<ClientData>, PlacedCamera)],
+    marker_size: f64,
+) -> Option<(f64, f64)> {
+    let (mut x, mut y) = (0., 0.);
+    let mut points = 0usize;
+
+    for (data, camera) in data {
+        let Some(data) = data else {
+            continue;
+        };
+        let Some(height) = data.marker_height else {
+            continue;
+        };
+        if height.is_nan() || height <= 0. {
+            continue;
+        }
+
+        let distance = marker_size / (2. * (0.5 * height).tan());
+        let angle = get_ray_angle(data, camera);
+
+        x += camera.position.x + distance * angle.cos();
+        y += camera.position.y + distance * angle.sin();
+
+        points += 1;
+    }
+
+    if points == 0 {
+        return None;
+    }
+
+    let points = points as f64;
+    Some((x / points, y / points))
+}
+
+fn get_rotation(
+    x: f64,
+    y: f64,
+    motion_data: Option<MotionData>,
+    compass_data: Option<f64>,
+    last_position: Option<Position>,
+) -> f64 {
     let comp_rot = compass_data;
     let pos_rot = get_pos_based_rotation(x, y, motion_data, last_position);
 
@@ -76,9 +156,69 @@ pub fn calculate_position(
         r += rot;
         rc += 1;
     }
-    let r = if rc == 0 { f64::NAN } else { r / rc as f64 };
 
-    Some(Position::new(x, y, r))
+    if rc == 0 {
+        f64::NAN
+    } else {
+        r / rc as f64
+    }
+}
+
+/// Fuses the marker poses seen by the cameras into the pose of the cube,
+/// assuming the markers cover the faces of the cube
+pub fn calculate_pose(
+    data: &[(Option<MarkerPose>, PlacedCamera)],
+    marker_size: f64,
+    cube: [u16; 4],
+) -> Option<CubePose> {
+    let camera_from_opencv = Pose3::camera_from_opencv();
+
+    let mut center = [0.; 3];
+    let mut rotations = vec![];
+
+    for (pose, camera) in data {
+        let Some(pose) = pose else {
+            continue;
+        };
+        let Some(face) = cube.iter().position(|id| *id == pose.marker_id) else {
+            continue;
+        };
+
+        let opencv_from_marker = Pose3::new(
+            pose.tvec.map(|v| v * marker_size),
+            Quaternion::from_rotation_vector(pose.rvec),
+        );
+        let world_from_marker = camera.pose() * camera_from_opencv * opencv_from_marker;
+
+        // the marker's z axis is the outwards normal of the face
+        let c = world_from_marker.transform_point([0., 0., -0.5 * marker_size]);
+        for (s, c) in center.iter_mut().zip(c) {
+            *s += c;
+        }
+
+        rotations.push(world_from_marker.rotation * cube_from_marker(face).conjugate());
+    }
+
+    let poses = rotations.len() as f64;
+    let (yaw, pitch, roll) = Quaternion::average(rotations)?.to_yaw_pitch_roll();
+    let [x, y, z] = center.map(|v| v / poses);
+
+    Some(CubePose {
+        x,
+        y,
+        z,
+        yaw,
+        pitch,
+        roll,
+    })
+}
+
+/// cube from marker rotation of the `face`th marker (counterclockwise from above)
+fn cube_from_marker(face: usize) -> Quaternion {
+    let (s, c) = (face as f64 * 90f64.to_radians()).sin_cos();
+
+    // columns: marker x (right), y (up), z (outwards normal)
+    Quaternion::from_rotation_matrix([[-s, 0., c], [c, 0., s], [0., 1., 0.]])
 }
 
 fn get_pos_based_rotation(
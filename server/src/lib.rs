@@ -3,11 +3,12 @@ use std::{
     time::{Duration, Instant},
 };
 
-pub use camloc_common::{hosts::constants::MAIN_PORT, Position};
+pub use camloc_common::{hosts::constants::MAIN_PORT, PlacedCamera, Pose3, Position};
 
 mod calc;
 pub mod compass;
 pub mod extrapolations;
+pub mod refine;
 pub mod service;
 
 #[derive(Clone, Copy)]
@@ -18,23 +19,48 @@ pub enum MotionHint {
     Stationary,
 }
 
+/// How a position was obtained
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FixKind {
+    /// Intersection of at least two camera rays
+    Triangulated,
+    /// A single camera ray with the range estimated from the apparent marker size,
+    /// less reliable than a triangulated fix
+    Ranged,
+}
+
+/// Full 3D pose of the cube, `z` points up, angles are **in radians**
 #[derive(Debug, PartialEq, Clone, Copy)]
 #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
-pub struct PlacedCamera {
-    /// Horizontal FOV (**in radians**)
-    pub fov: f64,
-    pub position: Position,
+pub struct CubePose {
+    pub x: f64,
+    pub y: f64,
+    pub z: f64,
+    pub yaw: f64,
+    pub pitch: f64,
+    pub roll: f64,
 }
 
-impl PlacedCamera {
-    pub fn new(position: Position, fov: f64) -> Self {
-        Self { position, fov }
+impl Display for CubePose {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "({:.2}; {:.2}; {:.2}; {:.2}°; {:.2}°; {:.2}°)",
+            self.x,
+            self.y,
+            self.z,
+            self.yaw.to_degrees(),
+            self.pitch.to_degrees(),
+            self.roll.to_degrees(),
+        )
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct TimedPosition {
     pub position: Position,
+    pub fix: FixKind,
     start_time: Instant,
     pub time: Instant,
 
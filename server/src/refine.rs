@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    f64::consts::{PI, TAU},
+    net::SocketAddr,
+};
+
+use crate::{calc::rescale_bearing, PlacedCamera, Position};
+
+/// What a camera saw of the cube
+#[derive(Debug, Clone, Copy)]
+pub struct Observation {
+    pub address: SocketAddr,
+    /// The placement of the camera when it saw the cube
+    pub camera: PlacedCamera,
+    /// The fov the client measures its bearings with (the one it connected with)
+    pub reported_fov: f64,
+    pub bearing: f64,
+}
+
+/// Observations of the cube by different cameras at the same time
+pub type Sample = Vec<Observation>;
+
+#[derive(Debug, Clone, Copy)]
+pub struct RefineOptions {
+    /// Expected noise of the bearings (radians)
+    pub bearing_noise: f64,
+    /// How far off the camera positions are expected to be
+    pub position_prior: f64,
+    /// How far off the camera rotations are expected to be (radians)
+    pub rotation_prior: f64,
+    /// How far off the fovs are expected to be (radians)
+    pub fov_prior: f64,
+    pub iterations: usize,
+}
+
+impl Default for RefineOptions {
+    fn default() -> Self {
+        Self {
+            bearing_noise: 0.5f64.to_radians(),
+            position_prior: 0.25,
+            rotation_prior: 5f64.to_radians(),
+            fov_prior: 5f64.to_radians(),
+            iterations: 50,
+        }
+    }
+}
+
+/// The refined placements and how well the rays meet before and after
+#[derive(Debug, Clone)]
+pub struct Refinement {
+    pub cameras: Vec<(SocketAddr, PlacedCamera)>,
+    /// Rms angle between the rays and the triangulated cube (radians)
+    pub residual_before: f64,
+    pub residual_after: f64,
+    /// Number of samples that could be triangulated
+    pub samples: usize,
+}
+
+/// Jointly refines the positions, rotations and fovs of the cameras
+/// and the positions of the cube in the samples,
+/// so that the rays of the cameras meet as well as possible.
+///
+/// Rays meet just as well if everything is moved, turned or scaled together,
+/// so the cameras are held close to where they were placed (see `RefineOptions`).
+///
+/// `None` if no sample could be triangulated
+pub fn refine(samples: &[Sample], options: &RefineOptions) -> Option<Refinement> {
+    let mut addresses = vec![];
+    let mut cameras: Vec<Camera> = vec![];
+    let mut indices = HashMap::new();
+
+    // the latest placement of every camera
+    for o in samples.iter().flatten() {
+        let i = *indices.entry(o.address).or_insert_with(|| {
+            addresses.push(o.address);
+            cameras.push(Camera::new(o));
+            cameras.len() - 1
+        });
+        cameras[i] = Camera::new(o);
+    }
+
+    let samples: Vec<Vec<(usize, f64)>> = samples
+        .iter()
+        .map(|s| s.iter().map(|o| (indices[&o.address], o.bearing)).collect())
+        .filter(|s: &Vec<_>| s.len() >= 2)
+        .collect();
+
+    let mut points: Vec<_> = samples.iter().map(|s| triangulate(&cameras, s)).collect();
+    let residual_before = rms(&cameras, &samples, &points)?;
+
+    let mut residual = residual_before;
+    for _ in 0..options.iterations {
+        for (i, camera) in cameras.iter_mut().enumerate() {
+            let seen = samples.iter().zip(&points).filter_map(|(s, p)| {
+                let (_, bearing) = s.iter().find(|(c, _)| *c == i)?;
+                Some(((*p)?, *bearing))
+            });
+            camera.step(seen, options);
+        }
+        points = samples.iter().map(|s| triangulate(&cameras, s)).collect();
+
+        let r = rms(&cameras, &samples, &points)?;
+        let converged = (residual - r).abs() < 1e-9;
+        residual = r;
+        if converged {
+            break;
+        }
+    }
+
+    Some(Refinement {
+        cameras: addresses
+            .into_iter()
+            .zip(&cameras)
+            .map(|(a, c)| (a, c.placed()))
+            .collect(),
+        residual_before,
+        residual_after: residual,
+        samples: points.iter().flatten().count(),
+    })
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Camera {
+    /// x, y, rotation, fov
+    params: [f64; 4],
+    initial: [f64; 4],
+    reported_fov: f64,
+    camera: PlacedCamera,
+}
+
+impl Camera {
+    fn new(o: &Observation) -> Self {
+        let Position { x, y, rotation } = o.camera.position;
+        let params = [x, y, rotation, o.camera.fov];
+        Self {
+            params,
+            initial: params,
+            reported_fov: o.reported_fov,
+            camera: o.camera,
+        }
+    }
+
+    fn ray_angle(&self, bearing: f64) -> f64 {
+        self.params[2] + rescale_bearing(bearing, self.reported_fov, self.params[3])
+    }
+
+    /// The placement with the refined parameters, keeping the height and tilt
+    fn placed(&self) -> PlacedCamera {
+        let [x, y, rotation, fov] = self.params;
+        let mut camera = self.camera;
+        camera.set_position(Position::new(x, y, rotation));
+        camera.fov = fov;
+        camera
+    }
+
+    /// A Gauss-Newton step with the points held still
+    fn step(&mut self, seen: impl Iterator<Item = ([f64; 2], f64)>, options: &RefineOptions) {
+        let mut h = [[0.; 4]; 4];
+        let mut g = [0.; 4];
+
+        let mut add = |j: [f64; 4], r: f64| {
+            for a in 0..4 {
+                for b in 0..4 {
+                    h[a][b] += j[a] * j[b];
+                }
+                g[a] += j[a] * r;
+            }
+        };
+
+        let [_, _, _, fov] = self.params;
+        let f0 = (0.5 * self.reported_fov).tan();
+        let k = (0.5 * fov).tan() / f0;
+
+        let [x, y, ..] = self.params;
+        for ([px, py], bearing) in seen {
+            let (dx, dy) = (px - x, py - y);
+            let d2 = dx * dx + dy * dy;
+            if d2 < f64::EPSILON {
+                continue;
+            }
+
+            let r = wrap(dy.atan2(dx) - self.ray_angle(bearing));
+
+            // d(ray angle) / d(fov)
+            let t = bearing.tan();
+            let dfov = t / (1. + k * k * t * t) * 0.5 / ((0.5 * fov).cos().powi(2) * f0);
+
+            let s = options.bearing_noise;
+            add([dy / d2 / s, -dx / d2 / s, -1. / s, -dfov / s], r / s);
+        }
+
+        let priors = [
+            options.position_prior,
+            options.position_prior,
+            options.rotation_prior,
+            options.fov_prior,
+        ];
+        for (i, s) in priors.into_iter().enumerate() {
+            let mut j = [0.; 4];
+            j[i] = 1. / s;
+            add(j, (self.params[i] - self.initial[i]) / s);
+        }
+
+        let Some(delta) = solve(h, g) else {
+            return;
+        };
+        for (p, d) in self.params.iter_mut().zip(delta) {
+            *p -= d;
+        }
+        self.params[3] = self.params[3].clamp(0.01, PI - 0.01);
+    }
+}
+
+/// Where the rays of the sample meet, `None` if they're parallel
+fn triangulate(cameras: &[Camera], sample: &[(usize, f64)]) -> Option<[f64; 2]> {
+    // least squares intersection of the lines
+    let mut h = [[0.; 2]; 2];
+    let mut g = [0.; 2];
+    for (c, bearing) in sample {
+        let camera = &cameras[*c];
+        let (s, c) = camera.ray_angle(*bearing).sin_cos();
+        let n = [-s, c];
+        let d = n[0] * camera.params[0] + n[1] * camera.params[1];
+
+        for a in 0..2 {
+            for b in 0..2 {
+                h[a][b] += n[a] * n[b];
+            }
+            g[a] += n[a] * d;
+        }
+    }
+    let mut p = solve(h, g)?;
+
+    // then minimize the angles instead of the distances
+    for _ in 0..5 {
+        let mut h = [[0.; 2]; 2];
+        let mut g = [0.; 2];
+        for (c, bearing) in sample {
+            let camera = &cameras[*c];
+            let (dx, dy) = (p[0] - camera.params[0], p[1] - camera.params[1]);
+            let d2 = dx * dx + dy * dy;
+            if d2 < f64::EPSILON {
+                continue;
+            }
+
+            let r = wrap(dy.atan2(dx) - camera.ray_angle(*bearing));
+            let j = [-dy / d2, dx / d2];
+            for a in 0..2 {
+                for b in 0..2 {
+                    h[a][b] += j[a] * j[b];
+                }
+                g[a] += j[a] * r;
+            }
+        }
+
+        let Some(delta) = solve(h, g) else {
+            break;
+        };
+        p = [p[0] - delta[0], p[1] - delta[1]];
+    }
+
+    Some(p)
+}
+
+fn rms(
+    cameras: &[Camera],
+    samples: &[Vec<(usize, f64)>],
+    points: &[Option<[f64; 2]>],
+) -> Option<f64> {
+    let (mut sum, mut n) = (0., 0usize);
+    for (sample, p) in samples.iter().zip(points) {
+        let Some([px, py]) = p else {
+            continue;
+        };
+        for (c, bearing) in sample {
+            let camera = &cameras[*c];
+            let r = wrap(
+                (py - camera.params[1]).atan2(px - camera.params[0]) - camera.ray_angle(*bearing),
+            );
+            sum += r * r;
+            n += 1;
+        }
+    }
+
+    (n > 0).then(|| (sum / n as f64).sqrt())
+}
+
+/// wraps an angle into [-pi, pi)
+fn wrap(a: f64) -> f64 {
+    (a + PI).rem_euclid(TAU) - PI
+}
+
+/// solves `a * x = b` with gaussian elimination
+#[allow(clippy::needless_range_loop)]
+fn solve<const N: usize>(mut a: [[f64; N]; N], mut b: [f64; N]) -> Option<[f64; N]> {
+    for i in 0..N {
+        let pivot = (i..N).max_by(|x, y| a[*x][i].abs().total_cmp(&a[*y][i].abs()))?;
+        if a[pivot][i].abs() < 1e-12 {
+            return None;
+        }
+        a.swap(i, pivot);
+        b.swap(i, pivot);
+
+        for r in i + 1..N {
+            let f = a[r][i] / a[i][i];
+            for c in i..N {
+                a[r][c] -= f * a[i][c];
+            }
+            b[r] -= f * b[i];
+        }
+    }
+
+    let mut x = [0.; N];
+    for i in (0..N).rev() {
+        let s: f64 = (i + 1..N).map(|c| a[i][c] * x[c]).sum();
+        x[i] = (b[i] - s) / a[i][i];
+    }
+    Some(x)
+}
@@ -1,11 +1,15 @@
 use anyhow::Result;
 use async_trait::async_trait;
 use camloc_common::{
-    hosts::{constants::MAIN_PORT, ClientData, Command, HostInfo, HostState, HostType},
-    Position, TimeValidated,
+    hosts::{
+        constants::MAIN_PORT, ClientData, ClientTelemetry, Command, HostInfo, HostState, HostType,
+        MarkerPose,
+    },
+    Pose3, Position, TimeValidated,
 };
 use futures::future::try_join_all;
 use std::{
+    collections::HashMap,
     f64::NAN,
     net::{IpAddr, Ipv4Addr, SocketAddr},
     sync::Arc,
@@ -14,21 +18,50 @@ use std::{
 use tokio::{
     net::UdpSocket,
     spawn,
-    sync::{broadcast, Mutex, RwLock},
+    sync::{broadcast, mpsc, Mutex, RwLock},
     task::JoinHandle,
 };
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    calc::{calculate_position, MotionData},
+    calc::{calculate_pose, calculate_position, rescale_bearing, MotionData},
     compass::{Compass, NoCompass},
     extrapolations::{Extrapolation, LinearExtrapolation},
-    MotionHint, PlacedCamera, TimedPosition,
+    refine::{refine, Observation, RefineOptions, Refinement, Sample},
+    CubePose, FixKind, MotionHint, PlacedCamera, TimedPosition,
 };
 
+/// An `INFO_UPDATE` for a client that hasn't connected yet,
+/// the organizer may send it before the client's `CONNECT` arrives
+struct PendingUpdate {
+    position: Position,
+    fov: Option<f64>,
+    mount: Option<Pose3>,
+    received: Instant,
+}
+
+impl PendingUpdate {
+    /// how long an update waits for its client
+    const VALIDITY: Duration = Duration::from_secs(30);
+
+    fn apply(&self, camera: &mut PlacedCamera) {
+        if let Some(mount) = self.mount {
+            camera.set_mount(mount);
+        } else {
+            camera.set_position(self.position);
+        }
+        if let Some(fov) = self.fov {
+            camera.fov = fov;
+        }
+    }
+}
+
 struct Client {
     last_data: TimeValidated<ClientData>,
+    last_pose: TimeValidated<MarkerPose>,
     camera: PlacedCamera,
+    /// the fov the client measures its bearings with
+    reported_fov: f64,
     address: SocketAddr,
 }
 
@@ -37,16 +70,32 @@ struct Client {
 pub enum Event {
     Connect(SocketAddr, PlacedCamera),
     Disconnect(SocketAddr),
-    PositionUpdate(Position),
+    PositionUpdate(Position, FixKind),
+    PoseUpdate(CubePose),
     InfoUpdate(SocketAddr, PlacedCamera),
+    Telemetry(SocketAddr, ClientTelemetry),
+}
+
+fn invalid_pose(valid_time: Duration) -> TimeValidated<MarkerPose> {
+    TimeValidated::new_with_change(
+        MarkerPose::new(u16::MAX, [0.; 3], [f64::NAN; 3]),
+        valid_time,
+        Instant::now() - valid_time,
+    )
 }
 
 struct Shared<E> {
     last_known_pos: RwLock<Option<TimedPosition>>,
+    last_known_pose: RwLock<Option<TimeValidated<CubePose>>>,
+    client_telemetry: RwLock<HashMap<SocketAddr, ClientTelemetry>>,
     motion_data: RwLock<Option<MotionData>>,
     event_tx: broadcast::Sender<Event>,
     cancel_token: CancellationToken,
     extrapolation: Mutex<E>,
+    /// what the cameras saw together, while recording for a refinement
+    recording: Mutex<Option<Vec<Sample>>>,
+    /// new placements for the connected clients, e.g. from a refinement
+    placement_tx: mpsc::UnboundedSender<(SocketAddr, PlacedCamera)>,
 }
 
 pub struct LocationService<E> {
@@ -60,6 +109,7 @@ pub struct Builder<C, E> {
     motion_data: Option<MotionData>,
     cancel_token: CancellationToken,
     min_camera_angle_diff: f64,
+    marker_size: Option<f64>,
     data_validity: Duration,
     clients: Vec<Client>,
     address: SocketAddr,
@@ -76,6 +126,7 @@ impl Builder<NoCompass, LinearExtrapolation> {
             extrapolation: LinearExtrapolation::new(),
             cancel_token: CancellationToken::new(),
             last_known_pos: None,
+            marker_size: None,
             compass: NoCompass,
             motion_data: None,
             clients: vec![],
@@ -95,6 +146,11 @@ impl<C, E> Builder<C, E> {
         self.min_camera_angle_diff = v;
         self
     }
+    /// Physical side length of the cube markers, enables single camera fixes
+    pub fn with_marker_size(mut self, v: f64) -> Self {
+        self.marker_size = Some(v);
+        self
+    }
     pub fn with_data_validity(mut self, v: Duration) -> Self {
         self.data_validity = v;
         self
@@ -106,7 +162,9 @@ impl<C, E> Builder<C, E> {
         address: SocketAddr,
     ) -> Self {
         self.clients.push(Client {
+            last_pose: invalid_pose(last_data.valid_time),
             last_data,
+            reported_fov: camera.fov,
             camera,
             address,
         });
@@ -128,6 +186,7 @@ impl<C, E> Builder<C, E> {
             clients: self.clients,
             data_validity: self.data_validity,
             min_camera_angle_diff: self.min_camera_angle_diff,
+            marker_size: self.marker_size,
             last_known_pos: self.last_known_pos,
             motion_data: self.motion_data,
             cancel_token: self.cancel_token,
@@ -140,6 +199,7 @@ impl<C, E> Builder<C, E> {
             clients: self.clients,
             data_validity: self.data_validity,
             min_camera_angle_diff: self.min_camera_angle_diff,
+            marker_size: self.marker_size,
             extrapolation: self.extrapolation,
             last_known_pos: self.last_known_pos,
             motion_data: self.motion_data,
@@ -161,21 +221,29 @@ impl<C: Compass + 'static, E: Extrapolation + 'static> Builder<C, E> {
 
         let (event_tx, event_rx) = broadcast::channel(1024);
         drop(event_rx);
+        let (placement_tx, placement_rx) = mpsc::unbounded_channel();
 
         let instance = Shared {
             last_known_pos: self.last_known_pos.into(),
+            last_known_pose: None.into(),
+            client_telemetry: HashMap::new().into(),
             extrapolation: self.extrapolation.into(),
             motion_data: self.motion_data.into(),
             cancel_token: self.cancel_token,
             event_tx: event_tx.clone(),
+            recording: None.into(),
+            placement_tx,
         };
         let shared_handle = Arc::new(instance);
 
         let background = Background {
             min_camera_angle_diff: self.min_camera_angle_diff,
+            marker_size: self.marker_size,
             data_validity: self.data_validity,
             shared: shared_handle.clone(),
             clients: self.clients,
+            pending_updates: HashMap::new(),
+            placement_rx,
             compass: self.compass,
             start_time,
             event_tx,
@@ -193,9 +261,13 @@ impl<C: Compass + 'static, E: Extrapolation + 'static> Builder<C, E> {
 struct Background<C, E> {
     event_tx: broadcast::Sender<Event>,
     min_camera_angle_diff: f64,
+    marker_size: Option<f64>,
     data_validity: Duration,
     shared: Arc<Shared<E>>,
     clients: Vec<Client>,
+    /// updates for clients that haven't connected yet, by the address the organizer sent
+    pending_updates: HashMap<String, PendingUpdate>,
+    placement_rx: mpsc::UnboundedReceiver<(SocketAddr, PlacedCamera)>,
     start_time: Instant,
     compass: C,
 }
@@ -206,7 +278,7 @@ impl<C: Compass, E: Extrapolation> Background<C, E> {
     }
 
     async fn run(mut self, sock: UdpSocket) -> Result<()> {
-        let mut buf = [0u8; 64];
+        let mut buf = [0u8; 256];
 
         let (cube, _organizer) = loop {
             let (len, addr) = tokio::select! {
@@ -237,6 +309,10 @@ impl<C: Compass, E: Extrapolation> Background<C, E> {
         loop {
             let (recv_len, recv_addr) = tokio::select! {
                 r = sock.recv_from(&mut buf) => r,
+                Some((address, camera)) = self.placement_rx.recv() => {
+                    self.place_client(address, camera);
+                    continue;
+                }
                 _ = self.shared.cancel_token.cancelled() => return Ok(())
             }?;
 
@@ -258,59 +334,63 @@ impl<C: Compass, E: Extrapolation> Background<C, E> {
                 }
 
                 // update value
-                Ok(Command::ValueUpdate(ClientData {
-                    marker_id,
-                    x_position,
-                })) => {
-                    let received_data = ClientData::new(marker_id, x_position);
+                Ok(Command::ValueUpdate(received_data)) => {
                     println!("{received_data:?}");
 
-                    // update client data and position if the oldest data was updated
-
-                    let (mut oldest_data_age, mut oldest_data_index) = (self.start_time, 0);
-                    let mut updated_client_index = None;
-
-                    let mut data = vec![];
-
-                    for (i, c) in self.clients.iter_mut().enumerate() {
-                        let data_age = c.last_data.last_changed();
-                        if data_age < oldest_data_age {
-                            oldest_data_age = data_age;
-                            oldest_data_index = i;
-                        }
+                    self.update_client_data(recv_addr, recv_time, received_data, cube)
+                        .await?;
+                }
 
-                        let client_data = if c.address == recv_addr {
-                            c.last_data.set_with_time(received_data, recv_time);
-                            updated_client_index = Some(i);
+                // update full marker pose
+                Ok(Command::PoseUpdate(received_pose)) => {
+                    println!("{received_pose:?}");
 
-                            Some(received_data)
-                        } else {
-                            c.last_data.get().copied()
-                        };
+                    self.update_pose(recv_addr, recv_time, received_pose, cube)
+                        .await;
 
-                        data.push((client_data, c.camera));
-                    }
+                    // a pose also determines the planar bearing
+                    self.update_client_data(recv_addr, recv_time, received_pose.into(), cube)
+                        .await?;
+                }
 
-                    // if we had a legit update
-                    if let Some(client_index) = updated_client_index {
-                        // and it was the client that was last updated
-                        if oldest_data_index == client_index {
-                            self.update_position(recv_time, &data[..], cube).await?;
-                        }
-                    }
+                // only from connected clients, anyone could fill the map otherwise
+                Ok(Command::Telemetry(telemetry))
+                    if self.clients.iter().any(|c| c.address == recv_addr) =>
+                {
+                    self.shared
+                        .client_telemetry
+                        .write()
+                        .await
+                        .insert(recv_addr, telemetry);
+
+                    self.send_event(Event::Telemetry(recv_addr, telemetry));
                 }
 
                 // connection request
                 Ok(Command::Connect { position, fov }) => {
-                    let camera = PlacedCamera::new(position, fov);
+                    let mut camera = PlacedCamera::new(position, fov);
+
+                    // older organizers only send the ip
+                    let pending = self
+                        .pending_updates
+                        .remove(&recv_addr.to_string())
+                        .or_else(|| self.pending_updates.remove(&recv_addr.ip().to_string()));
+                    if let Some(update) =
+                        pending.filter(|u| u.received.elapsed() < PendingUpdate::VALIDITY)
+                    {
+                        update.apply(&mut camera);
+                    }
+
                     self.clients.push(Client {
                         address: recv_addr,
                         camera,
+                        reported_fov: fov,
                         last_data: TimeValidated::new_with_change(
-                            ClientData::new(255, NAN),
+                            ClientData::new(u16::MAX, NAN),
                             self.data_validity,
                             recv_time - self.data_validity,
                         ),
+                        last_pose: invalid_pose(self.data_validity),
                     });
 
                     self.send_event(Event::Connect(recv_addr, camera));
@@ -320,34 +400,44 @@ impl<C: Compass, E: Extrapolation> Background<C, E> {
                     client_ip,
                     position,
                     fov,
-                }) => 'update: {
-                    let ev = 'loopy: {
-                        for c in self.clients.iter_mut() {
-                            if c.address.ip().to_string() == client_ip {
-                                c.camera.position = position;
-                                if let Some(fov) = fov {
-                                    c.camera.fov = fov;
-                                }
-
-                                break 'loopy Event::InfoUpdate(c.address, c.camera);
-                            }
-                        }
-
-                        break 'update;
+                    mount,
+                }) => {
+                    let update = PendingUpdate {
+                        position,
+                        fov,
+                        mount,
+                        received: recv_time,
                     };
-                    self.send_event(ev);
+
+                    // older organizers only send the ip
+                    let client = self.clients.iter_mut().find(|c| {
+                        c.address.to_string() == client_ip
+                            || c.address.ip().to_string() == client_ip
+                    });
+
+                    if let Some(c) = client {
+                        update.apply(&mut c.camera);
+                        let ev = Event::InfoUpdate(c.address, c.camera);
+                        self.send_event(ev);
+                    } else {
+                        self.pending_updates
+                            .retain(|_, u| u.received.elapsed() < PendingUpdate::VALIDITY);
+                        self.pending_updates.insert(client_ip.to_string(), update);
+                    }
                 }
 
                 Ok(Command::Stop) => break,
 
                 Ok(Command::ClientDisconnect) => {
-                    for i in 0..self.clients.len() {
-                        if self.clients[i].address == recv_addr {
-                            self.clients.remove(i);
-
-                            self.send_event(Event::Disconnect(self.clients[i].address));
-                            break;
-                        }
+                    if let Some(i) = self.clients.iter().position(|c| c.address == recv_addr) {
+                        self.clients.remove(i);
+                        self.shared
+                            .client_telemetry
+                            .write()
+                            .await
+                            .remove(&recv_addr);
+
+                        self.send_event(Event::Disconnect(recv_addr));
                     }
                 }
 
@@ -365,23 +455,144 @@ impl<C: Compass, E: Extrapolation> Background<C, E> {
         Ok(())
     }
 
+    fn place_client(&mut self, address: SocketAddr, camera: PlacedCamera) {
+        let Some(c) = self.clients.iter_mut().find(|c| c.address == address) else {
+            return;
+        };
+
+        c.camera = camera;
+        self.send_event(Event::InfoUpdate(address, camera));
+    }
+
+    async fn update_client_data(
+        &mut self,
+        recv_addr: SocketAddr,
+        recv_time: Instant,
+        received_data: ClientData,
+        cube: [u16; 4],
+    ) -> Result<()> {
+        // update client data and position if the oldest data was updated
+
+        let (mut oldest_data_age, mut oldest_data_index) = (self.start_time, 0);
+        let mut updated_client_index = None;
+
+        let mut data = vec![];
+
+        for (i, c) in self.clients.iter_mut().enumerate() {
+            let data_age = c.last_data.last_changed();
+            if data_age < oldest_data_age {
+                oldest_data_age = data_age;
+                oldest_data_index = i;
+            }
+
+            let client_data = if c.address == recv_addr {
+                c.last_data.set_with_time(received_data, recv_time);
+                updated_client_index = Some(i);
+
+                Some(received_data)
+            } else {
+                c.last_data.get().copied()
+            };
+
+            // the fov may have been corrected since the client connected
+            let client_data = client_data.map(|d| ClientData {
+                bearing: rescale_bearing(d.bearing, c.reported_fov, c.camera.fov),
+                ..d
+            });
+
+            data.push((client_data, c.camera));
+        }
+
+        // if we had a legit update
+        if let Some(client_index) = updated_client_index {
+            // and it was the client that was last updated
+            if oldest_data_index == client_index {
+                self.record_sample().await;
+                self.update_position(recv_time, &data[..], cube).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn record_sample(&self) {
+        let mut recording = self.shared.recording.lock().await;
+        let Some(samples) = recording.as_mut() else {
+            return;
+        };
+
+        let sample: Sample = self
+            .clients
+            .iter()
+            .filter_map(|c| {
+                Some(Observation {
+                    address: c.address,
+                    camera: c.camera,
+                    reported_fov: c.reported_fov,
+                    bearing: c.last_data.get()?.bearing,
+                })
+            })
+            .collect();
+
+        if sample.len() >= 2 {
+            samples.push(sample);
+        }
+    }
+
+    async fn update_pose(
+        &mut self,
+        recv_addr: SocketAddr,
+        recv_time: Instant,
+        received_pose: MarkerPose,
+        cube: [u16; 4],
+    ) {
+        let Some(marker_size) = self.marker_size else {
+            return;
+        };
+
+        let mut data = vec![];
+        for c in self.clients.iter_mut() {
+            let pose = if c.address == recv_addr {
+                c.last_pose.set_with_time(received_pose, recv_time);
+                Some(received_pose)
+            } else {
+                c.last_pose.get().copied()
+            };
+
+            data.push((pose, c.camera));
+        }
+
+        let Some(pose) = calculate_pose(&data, marker_size, cube) else {
+            return;
+        };
+
+        *self.shared.last_known_pose.write().await = Some(TimeValidated::new_with_change(
+            pose,
+            self.data_validity,
+            recv_time,
+        ));
+
+        self.send_event(Event::PoseUpdate(pose));
+    }
+
     async fn update_position(
         &mut self,
         recv_time: Instant,
         data: &[(Option<ClientData>, PlacedCamera)],
-        cube: [u8; 4],
+        cube: [u16; 4],
     ) -> Result<()> {
         let compass_value = self.compass.get_value().await;
 
         let mut last_pos = self.shared.last_known_pos.write().await;
         let motion_data = *self.shared.motion_data.read().await;
 
-        let Some(position) = calculate_position(
+        let Some((position, fix)) = calculate_position(
             self.min_camera_angle_diff,
             data,
             motion_data,
             compass_value,
             last_pos.map(|p| p.position),
+            self.marker_size,
             cube,
         ) else {
             return Ok(());
@@ -392,6 +603,7 @@ impl<C: Compass, E: Extrapolation> Background<C, E> {
             extrapolated_by: None,
             time: recv_time,
             position,
+            fix,
         };
 
         *last_pos = Some(calculated_position);
@@ -402,7 +614,7 @@ impl<C: Compass, E: Extrapolation> Background<C, E> {
             .await
             .add_datapoint(calculated_position);
 
-        self.send_event(Event::PositionUpdate(calculated_position.position));
+        self.send_event(Event::PositionUpdate(calculated_position.position, fix));
 
         Ok(())
     }
@@ -413,6 +625,17 @@ pub trait LocationServiceTrait: Send + Sync {
     async fn set_motion_hint(&self, hint: Option<MotionHint>);
     fn get_event_channel(&self) -> broadcast::Receiver<Event>;
     async fn get_position(&self) -> Option<Position>;
+    async fn get_pose(&self) -> Option<CubePose>;
+    /// The last telemetry report of each connected client
+    async fn get_client_telemetry(&self) -> Vec<(SocketAddr, ClientTelemetry)>;
+    /// Starts recording what the cameras see together (dropping the last recording),
+    /// while the cube is moved around for `refine_cameras`
+    async fn start_recording(&self);
+    /// Stops recording and jointly refines the camera placements from it,
+    /// `None` if the cameras never saw the cube together
+    async fn refine_cameras(&self, options: RefineOptions) -> Option<Refinement>;
+    /// Moves the connected cameras to their refined placements
+    async fn apply_refinement(&self, refinement: &Refinement) -> Result<()>;
     async fn stop(self) -> Result<()>;
 }
 
@@ -452,6 +675,38 @@ impl<E: Extrapolation> LocationServiceTrait for LocationService<E> {
         ex.extrapolate(now)
     }
 
+    async fn get_pose(&self) -> Option<CubePose> {
+        let pose = self.service_handle.last_known_pose.read().await;
+        pose.as_ref().and_then(|p| p.get()).copied()
+    }
+
+    async fn get_client_telemetry(&self) -> Vec<(SocketAddr, ClientTelemetry)> {
+        let telemetry = self.service_handle.client_telemetry.read().await;
+        telemetry.iter().map(|(a, t)| (*a, *t)).collect()
+    }
+
+    async fn start_recording(&self) {
+        *self.service_handle.recording.lock().await = Some(vec![]);
+    }
+
+    async fn refine_cameras(&self, options: RefineOptions) -> Option<Refinement> {
+        let samples = self.service_handle.recording.lock().await.take()?;
+        tokio::task::spawn_blocking(move || refine(&samples, &options))
+            .await
+            .ok()
+            .flatten()
+    }
+
+    async fn apply_refinement(&self, refinement: &Refinement) -> Result<()> {
+        for placement in &refinement.cameras {
+            self.service_handle
+                .placement_tx
+                .send(*placement)
+                .map_err(|_| anyhow::Error::msg("Service background task stopped"))?;
+        }
+        Ok(())
+    }
+
     async fn stop(mut self) -> Result<()> {
         let Some(h) = self.service_task_handle.take() else {
             return Err(anyhow::Error::msg("Service background task already joined"));
@@ -0,0 +1,133 @@
+//! Joint refinement of the camera placements
+
+use camloc_server::{
+    refine::{refine, Observation, RefineOptions, Sample},
+    PlacedCamera, Position,
+};
+use std::{
+    f64::consts::{FRAC_PI_2, PI, TAU},
+    net::SocketAddr,
+};
+
+const FOV: f64 = 1.2;
+
+fn wrap(a: f64) -> f64 {
+    (a + PI).rem_euclid(TAU) - PI
+}
+
+fn address(i: usize) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, 2], 0xdddd + i as u16))
+}
+
+/// four cameras around the origin, looking at it
+fn cameras() -> Vec<PlacedCamera> {
+    (0..4)
+        .map(|i| {
+            let rotation = i as f64 * FRAC_PI_2;
+            let (s, c) = rotation.sin_cos();
+            PlacedCamera::new(Position::new(-3. * c, -3. * s, rotation), FOV)
+        })
+        .collect()
+}
+
+/// what the `actual` cameras see of the cube moved around the center,
+/// while they are thought to be `placed`, measuring with the `placed` fovs
+fn record(actual: &[PlacedCamera], placed: &[PlacedCamera]) -> Vec<Sample> {
+    let mut samples = vec![];
+    for x in -4..=4 {
+        for y in -4..=4 {
+            let (px, py) = (0.35 * x as f64, 0.35 * y as f64);
+
+            let sample = actual
+                .iter()
+                .zip(placed)
+                .enumerate()
+                .map(|(i, (actual, placed))| {
+                    let Position { x, y, rotation } = actual.position;
+                    let angle = wrap((py - y).atan2(px - x) - rotation);
+                    let bearing =
+                        (angle.tan() * (0.5 * placed.fov).tan() / (0.5 * actual.fov).tan()).atan();
+
+                    Observation {
+                        address: address(i),
+                        camera: *placed,
+                        reported_fov: placed.fov,
+                        bearing,
+                    }
+                })
+                .collect();
+            samples.push(sample);
+        }
+    }
+    samples
+}
+
+/// how far off the position, rotation and fov of the camera are
+fn errors(placed: &PlacedCamera, actual: &PlacedCamera) -> (f64, f64, f64) {
+    let (p, a) = (placed.position, actual.position);
+    (
+        (p.x - a.x).hypot(p.y - a.y),
+        wrap(p.rotation - a.rotation).abs(),
+        (placed.fov - actual.fov).abs(),
+    )
+}
+
+#[test]
+fn exact_placements_stay() {
+    let cameras = cameras();
+    let refinement = refine(&record(&cameras, &cameras), &RefineOptions::default()).unwrap();
+
+    assert!(refinement.residual_before < 1e-9);
+    assert!(refinement.residual_after < 1e-9);
+    assert_eq!(refinement.samples, 81);
+
+    for (i, (address, camera)) in refinement.cameras.iter().enumerate() {
+        assert_eq!(*address, self::address(i));
+        let (position, rotation, fov) = errors(camera, &cameras[i]);
+        assert!(position < 1e-6 && rotation < 1e-6 && fov < 1e-6);
+    }
+}
+
+#[test]
+fn perturbed_placements_converge() {
+    let actual = cameras();
+    let mut placed = actual.clone();
+    placed[1].set_position(Position::new(0.15, -3.1, FRAC_PI_2 + 3f64.to_radians()));
+    placed[2].fov = FOV + 3f64.to_radians();
+
+    let refinement = refine(&record(&actual, &placed), &RefineOptions::default()).unwrap();
+
+    assert!(refinement.residual_after < 0.1 * refinement.residual_before);
+
+    // everything may move a bit together, the rays meet just as well
+    let refined: Vec<_> = refinement
+        .cameras
+        .iter()
+        .zip(&actual)
+        .map(|((_, refined), actual)| errors(refined, actual))
+        .collect();
+    for (position, rotation, fov) in &refined {
+        assert!(*position < 0.1, "{refined:?}");
+        assert!(*rotation < 1f64.to_radians(), "{refined:?}");
+        assert!(*fov < 1.5f64.to_radians(), "{refined:?}");
+    }
+
+    // but the perturbed placements moved back
+    let (position, rotation, _) = errors(&placed[1], &actual[1]);
+    assert!(refined[1].0 < 0.6 * position && refined[1].1 < 0.5 * rotation);
+    let (_, _, fov) = errors(&placed[2], &actual[2]);
+    assert!(refined[2].2 < 0.5 * fov);
+}
+
+#[test]
+fn nothing_to_refine_without_samples() {
+    assert!(refine(&[], &RefineOptions::default()).is_none());
+
+    // a single camera can't triangulate
+    let cameras = cameras();
+    let samples: Vec<Sample> = record(&cameras, &cameras)
+        .into_iter()
+        .map(|s| s.into_iter().take(1).collect())
+        .collect();
+    assert!(refine(&samples, &RefineOptions::default()).is_none());
+}